@@ -0,0 +1,572 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Global allocators built on mappings
+
+use super::backend::{Backend, Libc};
+use super::errno;
+use super::page::PageSize;
+use super::{perms, Map, Private};
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::UnsafeCell;
+use std::ptr::{copy_nonoverlapping, null_mut};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The `Libc` backend without the hooks (see `Backend::HOOKS`)
+///
+/// The observer may allocate, which would recurse into the allocator.
+struct Quiet;
+
+impl Backend for Quiet {
+    const HOOKS: bool = false;
+
+    #[inline]
+    unsafe fn mmap(
+        addr: usize,
+        size: usize,
+        prot: libc::c_int,
+        flags: libc::c_int,
+        fd: libc::c_int,
+        offset: libc::off_t,
+    ) -> errno::Result<usize> {
+        Libc::mmap(addr, size, prot, flags, fd, offset)
+    }
+
+    #[inline]
+    unsafe fn munmap(addr: usize, size: usize) -> errno::Result<()> {
+        Libc::munmap(addr, size)
+    }
+
+    #[inline]
+    unsafe fn mprotect(addr: usize, size: usize, prot: libc::c_int) -> errno::Result<()> {
+        Libc::mprotect(addr, size, prot)
+    }
+
+    #[inline]
+    unsafe fn mremap(
+        addr: usize,
+        size: usize,
+        new_size: usize,
+        flags: libc::c_int,
+        new_addr: usize,
+    ) -> errno::Result<usize> {
+        Libc::mremap(addr, size, new_size, flags, new_addr)
+    }
+
+    #[inline]
+    unsafe fn madvise(addr: usize, size: usize, advice: libc::c_int) -> errno::Result<()> {
+        Libc::madvise(addr, size, advice)
+    }
+}
+
+/// A mapping of an allocator
+type Raw<T> = Map<T, Private, Quiet>;
+
+/// An allocator which serves large allocations directly from mappings
+///
+/// Allocations of at least the threshold are served from anonymous
+/// mappings of their own. Growing and shrinking them uses `mremap()`, so
+/// the data is never copied. All other allocations, including those which
+/// must be aligned to more than a page, are passed on to the `System`
+/// allocator.
+///
+/// Nothing is allocated from the heap while serving an allocation, so
+/// this can be used as the global allocator:
+///
+/// ```rust
+/// use mmarinus::alloc::MmapAlloc;
+///
+/// #[global_allocator]
+/// static ALLOC: MmapAlloc = MmapAlloc::new(1 << 20).with_transparent_huge_pages();
+///
+/// let mut big = vec![0u8; 4 << 20];
+/// big[0] = 1;
+/// big.resize(8 << 20, 2);
+/// assert_eq!(big[0], 1);
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MmapAlloc {
+    threshold: usize,
+    huge: bool,
+}
+
+impl Default for MmapAlloc {
+    #[inline]
+    fn default() -> Self {
+        Self::new(Self::THRESHOLD)
+    }
+}
+
+impl MmapAlloc {
+    /// The default threshold (this is also the default of glibc's `malloc()`)
+    pub const THRESHOLD: usize = 128 * 1024;
+
+    /// Creates an allocator which maps allocations of at least `threshold` bytes
+    #[inline]
+    pub const fn new(threshold: usize) -> Self {
+        Self {
+            threshold,
+            huge: false,
+        }
+    }
+
+    /// Advises the kernel to back mapped allocations with transparent huge pages
+    ///
+    /// This uses `MADV_HUGEPAGE`. The advice is ignored if transparent huge
+    /// pages are not enabled.
+    #[inline]
+    pub const fn with_transparent_huge_pages(self) -> Self {
+        Self { huge: true, ..self }
+    }
+
+    /// Returns `true` if an allocation with this layout is mapped
+    #[inline]
+    fn mapped(&self, layout: &Layout) -> bool {
+        layout.size() >= self.threshold && layout.align() <= PageSize::base().bytes()
+    }
+
+    /// Gives the transparent huge page advice for an allocation, if enabled
+    #[inline]
+    fn advise(&self, addr: *mut u8, size: usize) {
+        if self.huge {
+            let _ = unsafe { Quiet::madvise(addr as usize, size, libc::MADV_HUGEPAGE) };
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for MmapAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if !self.mapped(&layout) {
+            return System.alloc(layout);
+        }
+
+        let map = Map::bytes(layout.size())
+            .anywhere()
+            .anonymously()
+            .with_backend::<Quiet>()
+            .with(perms::ReadWrite);
+
+        match map {
+            Err(..) => null_mut(),
+            Ok(map) => {
+                let (addr, size) = map.into_raw();
+                self.advise(addr as *mut u8, size);
+                addr as *mut u8
+            }
+        }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        // Anonymous mappings are always zeroed.
+        match self.mapped(&layout) {
+            true => self.alloc(layout),
+            false => System.alloc_zeroed(layout),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match self.mapped(&layout) {
+            false => System.dealloc(ptr, layout),
+            true => drop(Raw::<perms::ReadWrite>::from_raw(
+                ptr as usize,
+                layout.size(),
+            )),
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = Layout::from_size_align_unchecked(new_size, layout.align());
+
+        match (self.mapped(&layout), self.mapped(&new)) {
+            (false, false) => System.realloc(ptr, layout, new_size),
+
+            (true, true) => {
+                let flags = libc::MREMAP_MAYMOVE;
+                match Quiet::mremap(ptr as usize, layout.size(), new_size, flags, 0) {
+                    Err(..) => null_mut(),
+                    Ok(addr) => {
+                        self.advise(addr as *mut u8, new_size);
+                        addr as *mut u8
+                    }
+                }
+            }
+
+            _ => {
+                let ret = self.alloc(new);
+                if !ret.is_null() {
+                    copy_nonoverlapping(ptr, ret, layout.size().min(new_size));
+                    self.dealloc(ptr, layout);
+                }
+
+                ret
+            }
+        }
+    }
+}
+
+/// The number of freed allocations `GuardAlloc` can keep in quarantine
+const SLOTS: usize = 4096;
+
+/// The number of freed allocations `GuardAlloc` unmaps at a time
+const EVICT: usize = 32;
+
+/// Freed allocations, oldest first
+struct Quarantine {
+    slots: [(usize, usize); SLOTS],
+    head: usize,
+    len: usize,
+    bytes: usize,
+}
+
+/// A debugging allocator which detects overruns and use after free
+///
+/// Each allocation gets a mapping of its own, followed by a `PROT_NONE`
+/// guard page. The allocation is placed at the very end of the accessible
+/// pages, so reading or writing past its end faults immediately. (Since
+/// the start of the allocation has to be aligned, up to `align - 1` bytes
+/// of overrun can go undetected.) With `GuardAlloc::with_underflow()`, the
+/// guard page is placed before the allocation instead, which is placed at
+/// the very start of the accessible pages.
+///
+/// When an allocation is freed, its pages are made inaccessible but stay
+/// mapped for as long as they are in quarantine, so that any use after free
+/// faults as well. Once the quarantine exceeds its size limit, the oldest
+/// allocations are unmapped.
+///
+/// Allocations which must be aligned to more than a page are passed on to
+/// the `System` allocator.
+///
+/// ```rust
+/// use mmarinus::alloc::GuardAlloc;
+///
+/// #[global_allocator]
+/// static ALLOC: GuardAlloc = GuardAlloc::new(64 << 20);
+///
+/// let v = vec![1u8, 2, 3];
+/// assert_eq!(v.iter().sum::<u8>(), 6);
+/// ```
+pub struct GuardAlloc {
+    limit: usize,
+    underflow: bool,
+    lock: AtomicBool,
+    quarantine: UnsafeCell<Quarantine>,
+}
+
+// The quarantine is only accessed with the lock held.
+unsafe impl Sync for GuardAlloc {}
+
+impl std::fmt::Debug for GuardAlloc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GuardAlloc")
+            .field("limit", &self.limit)
+            .field("underflow", &self.underflow)
+            .finish_non_exhaustive()
+    }
+}
+
+impl GuardAlloc {
+    /// Creates an allocator which keeps up to `limit` bytes of freed allocations
+    ///
+    /// The limit counts whole pages, including the guard pages. Regardless
+    /// of the limit, at most 4096 freed allocations are kept.
+    #[inline]
+    pub const fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            underflow: false,
+            lock: AtomicBool::new(false),
+            quarantine: UnsafeCell::new(Quarantine {
+                slots: [(0, 0); SLOTS],
+                head: 0,
+                len: 0,
+                bytes: 0,
+            }),
+        }
+    }
+
+    /// Detects underflows instead of overflows
+    #[inline]
+    pub const fn with_underflow(self) -> Self {
+        Self {
+            underflow: true,
+            ..self
+        }
+    }
+
+    /// Gets the number of bytes currently in quarantine
+    pub fn quarantined(&self) -> usize {
+        self.locked(|q| q.bytes)
+    }
+
+    fn locked<R>(&self, f: impl FnOnce(&mut Quarantine) -> R) -> R {
+        while (self.lock)
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            std::hint::spin_loop();
+        }
+
+        let ret = f(unsafe { &mut *self.quarantine.get() });
+        self.lock.store(false, Ordering::Release);
+        ret
+    }
+
+    /// Gets the mapping (including the guard page) of an allocation
+    fn region(&self, ptr: *mut u8, layout: &Layout) -> (usize, usize) {
+        let page = PageSize::base();
+        let data = page.round_up(layout.size()).unwrap_or(0);
+
+        match self.underflow {
+            true => (ptr as usize - page.bytes(), data + page.bytes()),
+            false => (ptr as usize & !(page.bytes() - 1), data + page.bytes()),
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for GuardAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let page = PageSize::base().bytes();
+        if layout.align() > page {
+            return System.alloc(layout);
+        }
+
+        let data = match PageSize::base().round_up(layout.size()) {
+            Some(data) => data,
+            None => return null_mut(),
+        };
+
+        let map = match data.checked_add(page) {
+            None => return null_mut(),
+            Some(size) => Map::bytes(size)
+                .anywhere()
+                .anonymously()
+                .with_backend::<Quiet>()
+                .with(perms::ReadWrite),
+        };
+
+        let split = match (map, self.underflow) {
+            (Err(..), _) => return null_mut(),
+            (Ok(map), false) => map.split(data).map(|(l, r)| (r, l)),
+            (Ok(map), true) => map.split(page),
+        };
+
+        let (guard, map) = match split {
+            Err(..) => return null_mut(),
+            Ok(split) => split,
+        };
+
+        match guard.reprotect(perms::None) {
+            Err(..) => return null_mut(),
+            Ok(guard) => {
+                guard.into_raw();
+            }
+        }
+
+        let addr = match self.underflow {
+            true => map.addr(),
+            false => (map.addr() + data - layout.size()) & !(layout.align() - 1),
+        };
+
+        map.into_raw();
+        addr as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if layout.align() > PageSize::base().bytes() {
+            return System.dealloc(ptr, layout);
+        }
+
+        let (addr, size) = self.region(ptr, &layout);
+        if let Ok(map) = Raw::<perms::ReadWrite>::from_raw(addr, size).reprotect(perms::None) {
+            map.into_raw();
+        }
+
+        // Unmap outside of the lock, in batches of a fixed size, so that
+        // nothing is allocated and the lock is never held across a call.
+        let limit = self.limit;
+        loop {
+            let mut evicted = [(0, 0); EVICT];
+            let mut count = 0;
+
+            let done = self.locked(|q| {
+                while q.len > 0 && (q.len == SLOTS || q.bytes + size > limit) {
+                    if count == EVICT {
+                        return false;
+                    }
+
+                    evicted[count] = q.slots[q.head];
+                    q.head = (q.head + 1) % SLOTS;
+                    q.bytes -= evicted[count].1;
+                    q.len -= 1;
+                    count += 1;
+                }
+
+                if size <= limit {
+                    q.slots[(q.head + q.len) % SLOTS] = (addr, size);
+                    q.bytes += size;
+                    q.len += 1;
+                }
+
+                true
+            });
+
+            for (addr, size) in &evicted[..count] {
+                drop(Raw::<perms::None>::from_raw(*addr, *size));
+            }
+
+            if done {
+                break;
+            }
+        }
+
+        if size > limit {
+            drop(Raw::<perms::None>::from_raw(addr, size));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mapped() {
+        let alloc = MmapAlloc::new(1 << 16).with_transparent_huge_pages();
+        let page = PageSize::base();
+
+        unsafe {
+            let layout = Layout::from_size_align(1 << 16, 8).unwrap();
+            let ptr = alloc.alloc(layout);
+            assert!(page.is_aligned(ptr as usize));
+            ptr.add(layout.size() - 1).write(1);
+
+            let ptr = alloc.realloc(ptr, layout, 1 << 20);
+            assert_eq!(ptr.add(layout.size() - 1).read(), 1);
+            assert_eq!(ptr.add((1 << 20) - 1).read(), 0);
+
+            // The mappings of the allocator are not recorded.
+            #[cfg(feature = "tracking")]
+            assert!(crate::tracking::snapshot()
+                .iter()
+                .all(|r| r.addr != ptr as usize));
+
+            let layout = Layout::from_size_align(1 << 20, 8).unwrap();
+            let ptr = alloc.realloc(ptr, layout, 16);
+            assert_eq!(ptr.add(15).read(), 0);
+
+            let layout = Layout::from_size_align(16, 8).unwrap();
+            alloc.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn small() {
+        let alloc = MmapAlloc::default();
+
+        unsafe {
+            let layout = Layout::from_size_align(64, 8).unwrap();
+            let ptr = alloc.alloc_zeroed(layout);
+            assert_eq!(ptr.read(), 0);
+            ptr.write(3);
+
+            let ptr = alloc.realloc(ptr, layout, MmapAlloc::THRESHOLD);
+            assert!(PageSize::base().is_aligned(ptr as usize));
+            assert_eq!(ptr.read(), 3);
+
+            let layout = Layout::from_size_align(MmapAlloc::THRESHOLD, 8).unwrap();
+            alloc.dealloc(ptr, layout);
+        }
+
+        // Over-aligned allocations are never mapped.
+        let layout = Layout::from_size_align(MmapAlloc::THRESHOLD, 1 << 30).unwrap();
+        assert!(!alloc.mapped(&layout));
+    }
+
+    /// Gets the permissions of the mapping containing `addr`
+    fn perms(addr: usize) -> Option<String> {
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+        maps.lines().find_map(|line| {
+            let mut fields = line.split_whitespace();
+            let mut range = fields.next()?.split('-');
+            let start = usize::from_str_radix(range.next()?, 16).ok()?;
+            let end = usize::from_str_radix(range.next()?, 16).ok()?;
+            match start <= addr && addr < end {
+                true => fields.next().map(|p| p[..3].to_string()),
+                false => None,
+            }
+        })
+    }
+
+    #[test]
+    fn guard() {
+        let page = PageSize::base().bytes();
+        let alloc = GuardAlloc::new(page * 4);
+
+        unsafe {
+            let layout = Layout::from_size_align(10, 2).unwrap();
+            let ptr = alloc.alloc(layout) as usize;
+            assert_eq!(ptr % 2, 0);
+            assert_eq!(perms(ptr).unwrap(), "rw-");
+            assert_eq!(perms(ptr + 9).unwrap(), "rw-");
+            assert_eq!(perms(ptr + 10).unwrap(), "---");
+
+            alloc.dealloc(ptr as *mut u8, layout);
+            assert_eq!(perms(ptr).unwrap(), "---");
+            assert_eq!(alloc.quarantined(), page * 2);
+
+            let other = alloc.alloc(layout);
+            alloc.dealloc(other, layout);
+            assert_eq!(alloc.quarantined(), page * 4);
+
+            // The oldest allocation is evicted from the quarantine.
+            let other = alloc.alloc(layout);
+            alloc.dealloc(other, layout);
+            assert_eq!(alloc.quarantined(), page * 4);
+        }
+    }
+
+    #[test]
+    fn evict() {
+        let page = PageSize::base().bytes();
+        let alloc = GuardAlloc::new(page * 2 * EVICT * 2);
+
+        unsafe {
+            let small = Layout::from_size_align(8, 8).unwrap();
+            let ptrs: Vec<_> = (0..EVICT * 2).map(|_| alloc.alloc(small)).collect();
+            for ptr in &ptrs {
+                alloc.dealloc(*ptr, small);
+            }
+
+            assert_eq!(alloc.quarantined(), page * 2 * EVICT * 2);
+
+            // Evicts more allocations than fit in one batch.
+            let pages = EVICT * 2 + 7;
+            let large = Layout::from_size_align(page * (pages - 1), 8).unwrap();
+            alloc.dealloc(alloc.alloc(large), large);
+
+            let evicted = (pages + 1) / 2;
+            assert!(evicted > EVICT);
+            assert_eq!(
+                alloc.quarantined(),
+                page * ((EVICT * 2 - evicted) * 2 + pages)
+            );
+            assert_eq!(perms(ptrs[EVICT * 2 - 1] as usize).unwrap(), "---");
+        }
+    }
+
+    #[test]
+    fn underflow() {
+        let page = PageSize::base().bytes();
+        let alloc = GuardAlloc::new(0).with_underflow();
+
+        unsafe {
+            let layout = Layout::from_size_align(page + 1, 8).unwrap();
+            let ptr = alloc.alloc(layout) as usize;
+            assert_eq!(ptr % page, 0);
+            assert_eq!(perms(ptr - 1).unwrap(), "---");
+            assert_eq!(perms(ptr + page).unwrap(), "rw-");
+
+            alloc.dealloc(ptr as *mut u8, layout);
+            assert_eq!(alloc.quarantined(), 0);
+        }
+    }
+}
@@ -0,0 +1,298 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Append-only logs written through shared file mappings
+
+use super::page::PageSize;
+use super::{perms, Map, Shared};
+
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::path::Path;
+
+const DEFAULT_CHUNK: usize = 1024 * 1024;
+
+/// Options for opening a `MappedAppender`
+///
+/// ```rust
+/// use mmarinus::appender::MappedAppender;
+///
+/// let path = std::env::temp_dir().join(format!("mmarinus-doc-{}.log", std::process::id()));
+///
+/// let mut log = MappedAppender::options()
+///     .chunk(64 * 1024)
+///     .sync_every(16 * 1024)
+///     .open(&path)
+///     .unwrap();
+///
+/// assert_eq!(log.append(b"hello ").unwrap(), 0);
+/// assert_eq!(log.append(b"world").unwrap(), 6);
+/// log.close().unwrap();
+///
+/// assert_eq!(std::fs::read(&path).unwrap(), b"hello world");
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct Options {
+    chunk: usize,
+    sync_every: Option<u64>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            chunk: DEFAULT_CHUNK,
+            sync_every: None,
+        }
+    }
+}
+
+impl Options {
+    /// Sets the number of bytes by which the file grows when it is full
+    ///
+    /// The value is rounded up to a multiple of the page size. The default
+    /// is 1 MiB.
+    #[inline]
+    pub fn chunk(&mut self, size: usize) -> &mut Self {
+        self.chunk = size;
+        self
+    }
+
+    /// Calls `msync()` each time at least `bytes` bytes have been appended
+    ///
+    /// By default, data is only synchronized when `MappedAppender::sync()`
+    /// is called or when the appender is closed.
+    #[inline]
+    pub fn sync_every(&mut self, bytes: u64) -> &mut Self {
+        self.sync_every = Some(bytes);
+        self
+    }
+
+    /// Opens (or creates) the log at `path`
+    ///
+    /// If the file already exists, new data is appended after its contents.
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> std::io::Result<MappedAppender> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let chunk = match PageSize::base().round_up(self.chunk) {
+            Some(chunk) if chunk > 0 => chunk,
+            _ => return Err(ErrorKind::InvalidInput.into()),
+        };
+
+        let len = file.metadata()?.len();
+        let mut log = MappedAppender {
+            file,
+            map: None,
+            len,
+            synced: len,
+            chunk,
+            sync_every: self.sync_every,
+        };
+
+        log.reserve(0)?;
+        Ok(log)
+    }
+}
+
+/// An append-only log backed by a shared file mapping
+///
+/// The file is grown in chunks and the mapping is recreated to cover the
+/// whole file each time it grows. When the appender is closed (or dropped),
+/// the file is truncated back to the length of the data actually appended.
+#[derive(Debug)]
+pub struct MappedAppender {
+    file: File,
+    map: Option<Map<perms::ReadWrite, Shared>>,
+    len: u64,
+    synced: u64,
+    chunk: usize,
+    sync_every: Option<u64>,
+}
+
+impl MappedAppender {
+    /// Returns the default options for opening an appender
+    #[inline]
+    pub fn options() -> Options {
+        Options::default()
+    }
+
+    /// Opens (or creates) the log at `path` with the default options
+    #[inline]
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Self::options().open(path)
+    }
+
+    /// Gets the logical length of the log
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns `true` if nothing has been written to the log
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Gets the number of bytes currently mapped (and allocated in the file)
+    #[inline]
+    pub fn capacity(&self) -> u64 {
+        self.map.as_ref().map(|m| m.size() as u64).unwrap_or(0)
+    }
+
+    /// Appends `data` to the log and returns the offset at which it starts
+    pub fn append(&mut self, data: &[u8]) -> std::io::Result<u64> {
+        let offset = self.len;
+        self.reserve(data.len())?;
+
+        if let Some(map) = self.map.as_mut() {
+            map.copy_from(
+                usize::try_from(offset).or(Err(ErrorKind::InvalidData))?,
+                data,
+            )?;
+        }
+
+        self.len += data.len() as u64;
+
+        if let Some(every) = self.sync_every {
+            if self.len - self.synced >= every {
+                self.sync()?;
+            }
+        }
+
+        Ok(offset)
+    }
+
+    /// Flushes all appended data to the file using `msync()`
+    pub fn sync(&mut self) -> std::io::Result<()> {
+        if let Some(map) = self.map.as_ref() {
+            let psize = PageSize::base().bytes() as u64;
+            let start = self.synced / psize * psize;
+            let size = usize::try_from(self.len - start).or(Err(ErrorKind::InvalidData))?;
+
+            if size > 0 {
+                let addr = map.addr() + usize::try_from(start).or(Err(ErrorKind::InvalidData))?;
+                if unsafe { libc::msync(addr as _, size, libc::MS_SYNC) } != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+        }
+
+        self.synced = self.len;
+        Ok(())
+    }
+
+    /// Synchronizes the data, unmaps the file and truncates it
+    ///
+    /// The file is truncated to the logical length of the log.
+    pub fn close(mut self) -> std::io::Result<()> {
+        self.finish()
+    }
+
+    /// Ensures that at least `additional` more bytes fit in the mapping
+    fn reserve(&mut self, additional: usize) -> std::io::Result<()> {
+        let needed = self
+            .len
+            .checked_add(additional as u64)
+            .ok_or(ErrorKind::InvalidInput)?;
+
+        if self.map.is_some() && needed <= self.capacity() {
+            return Ok(());
+        }
+
+        let chunk = self.chunk as u64;
+        let capacity = match needed.checked_add(chunk - 1) {
+            Some(x) => (x / chunk * chunk).max(chunk),
+            None => return Err(ErrorKind::InvalidInput.into()),
+        };
+
+        let size = usize::try_from(capacity).or(Err(ErrorKind::InvalidInput))?;
+        self.file.set_len(capacity)?;
+
+        // Drop the old mapping only once the new one has been created.
+        let map = Map::bytes(size)
+            .anywhere()
+            .from(&mut self.file, 0)
+            .with_kind(Shared)
+            .with(perms::ReadWrite)?;
+
+        self.map = Some(map);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        if self.map.is_some() {
+            self.sync()?;
+            self.map = None;
+            self.file.set_len(self.len)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for MappedAppender {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn path() -> PathBuf {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNT.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir().join(format!("mmarinus-appender-{}-{}", std::process::id(), n))
+    }
+
+    #[test]
+    fn grow() {
+        let path = path();
+        let psize = PageSize::base().bytes();
+
+        let mut log = MappedAppender::options().chunk(1).open(&path).unwrap();
+        assert!(log.is_empty());
+        assert_eq!(log.capacity(), psize as u64);
+
+        let block = vec![7u8; psize - 1];
+        assert_eq!(log.append(&block).unwrap(), 0);
+        assert_eq!(log.append(&block).unwrap(), psize as u64 - 1);
+        assert_eq!(log.len(), 2 * psize as u64 - 2);
+        assert_eq!(log.capacity(), 2 * psize as u64);
+
+        log.close().unwrap();
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            [block.clone(), block].concat()
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reopen() {
+        let path = path();
+
+        let mut log = MappedAppender::options().sync_every(1).open(&path).unwrap();
+        log.append(b"abc").unwrap();
+        drop(log);
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"abc");
+
+        let mut log = MappedAppender::open(&path).unwrap();
+        assert_eq!(log.append(b"def").unwrap(), 3);
+        log.sync().unwrap();
+        log.close().unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"abcdef");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
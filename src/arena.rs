@@ -0,0 +1,298 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use super::page::PageSize;
+use super::{perms, Error, Flags, Map};
+
+use std::cell::{Cell, RefCell};
+use std::io::ErrorKind;
+use std::mem::{align_of, size_of};
+use std::slice::from_raw_parts_mut;
+
+/// A bump allocator backed by an anonymous mapping
+///
+/// Allocations are carved out of the mapping by bumping a pointer, so they
+/// are very cheap, but individual allocations can never be freed. Instead,
+/// all allocations are released at once with `Arena::reset()`, which also
+/// returns the physical memory of the mapping to the system.
+///
+/// Allocations borrow the arena, so they cannot outlive a reset:
+///
+/// ```rust
+/// use mmarinus::Arena;
+///
+/// let mut arena = Arena::new(64 * 1024).unwrap();
+///
+/// let x = arena.alloc(7u64).unwrap();
+/// let buf = arena.alloc_bytes(100, 1).unwrap();
+/// buf[0] = 1;
+/// *x += 1;
+/// assert_eq!(*x, 8);
+///
+/// arena.reset();
+/// assert_eq!(arena.used(), 0);
+/// ```
+///
+/// The values of allocations are never dropped.
+#[derive(Debug)]
+pub struct Arena {
+    base: usize,
+    max: usize,
+    next: Cell<usize>,
+    committed: Cell<usize>,
+    parts: RefCell<Parts>,
+}
+
+/// The mappings of an arena, in address order
+#[derive(Debug)]
+struct Parts {
+    /// The accessible pages
+    committed: Vec<Map<perms::ReadWrite>>,
+
+    /// The inaccessible rest of the address space, if any
+    reserved: Option<Map<perms::None>>,
+}
+
+impl Arena {
+    /// Creates an arena of `size` bytes
+    ///
+    /// The size is rounded up to a multiple of the page size.
+    pub fn new(size: usize) -> Result<Self, Error<()>> {
+        Self::reserve(size, size)
+    }
+
+    /// Creates an arena of `size` bytes which can grow up to `max` bytes
+    ///
+    /// The address space for `max` bytes is reserved up front (with
+    /// `PROT_NONE` and `Flags::NORESERVE`) but only `size` bytes are
+    /// accessible. The arena grows into the reserved space as needed, so
+    /// allocations never move. Both sizes are rounded up to a multiple of
+    /// the page size.
+    pub fn reserve(size: usize, max: usize) -> Result<Self, Error<()>> {
+        let page = PageSize::base();
+        let (size, max) = match (page.round_up(size), page.round_up(max)) {
+            (Some(size), Some(max)) if size <= max && max > 0 => (size, max),
+            _ => return Err(ErrorKind::InvalidInput.into()),
+        };
+
+        let reserved = Map::bytes(max)
+            .anywhere()
+            .anonymously()
+            .with_flags(Flags::NORESERVE)
+            .with(perms::None)?;
+
+        let arena = Self {
+            base: reserved.addr(),
+            max,
+            next: Cell::new(0),
+            committed: Cell::new(0),
+            parts: RefCell::new(Parts {
+                committed: Vec::new(),
+                reserved: Some(reserved),
+            }),
+        };
+
+        arena.commit(size)?;
+        Ok(arena)
+    }
+
+    /// Gets the number of bytes allocated since the last reset
+    #[inline]
+    pub fn used(&self) -> usize {
+        self.next.get()
+    }
+
+    /// Gets the number of bytes currently accessible
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.committed.get()
+    }
+
+    /// Gets the maximum number of bytes the arena can grow to
+    #[inline]
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Allocates `len` bytes aligned to `align`
+    ///
+    /// The contents of the bytes are unspecified. Returns `None` if `align`
+    /// is not a power of two or if the arena is full.
+    #[allow(clippy::mut_from_ref)] // Allocations never overlap.
+    pub fn alloc_bytes(&self, len: usize, align: usize) -> Option<&mut [u8]> {
+        if !align.is_power_of_two() {
+            return None;
+        }
+
+        let base = self.base;
+        let start = (base + self.next.get()).checked_add(align - 1)? & !(align - 1);
+        let end = start.checked_add(len)?;
+        if end - base > self.committed.get() {
+            self.grow(end - base)?;
+        }
+
+        self.next.set(end - base);
+        Some(unsafe { from_raw_parts_mut(start as *mut u8, len) })
+    }
+
+    /// Allocates a value
+    ///
+    /// Returns `None` if the arena is full.
+    #[allow(clippy::mut_from_ref)] // Allocations never overlap.
+    pub fn alloc<T>(&self, value: T) -> Option<&mut T> {
+        let buf = self.alloc_bytes(size_of::<T>(), align_of::<T>())?;
+        let ptr = buf.as_mut_ptr() as *mut T;
+
+        unsafe {
+            ptr.write(value);
+            Some(&mut *ptr)
+        }
+    }
+
+    /// Allocates a copy of a slice
+    ///
+    /// Returns `None` if the arena is full.
+    #[allow(clippy::mut_from_ref)] // Allocations never overlap.
+    pub fn alloc_slice<T: Copy>(&self, values: &[T]) -> Option<&mut [T]> {
+        let len = size_of::<T>().checked_mul(values.len())?;
+        let buf = self.alloc_bytes(len, align_of::<T>())?;
+        let ptr = buf.as_mut_ptr() as *mut T;
+
+        unsafe {
+            ptr.copy_from_nonoverlapping(values.as_ptr(), values.len());
+            Some(from_raw_parts_mut(ptr, values.len()))
+        }
+    }
+
+    /// Releases all allocations
+    ///
+    /// The physical memory of the used pages is returned to the system with
+    /// `MADV_FREE` (or `MADV_DONTNEED` where that is not supported). The
+    /// accessible size of the arena is kept.
+    pub fn reset(&mut self) {
+        let used = PageSize::base().round_up(self.next.get()).unwrap_or(0);
+        let addr = self.base as *mut _;
+
+        if used > 0 && unsafe { libc::madvise(addr, used, libc::MADV_FREE) } != 0 {
+            // MADV_FREE was added in Linux 4.5.
+            unsafe { libc::madvise(addr, used, libc::MADV_DONTNEED) };
+        }
+
+        self.next.set(0);
+    }
+
+    /// Makes at least `needed` bytes accessible
+    fn grow(&self, needed: usize) -> Option<()> {
+        let committed = self.committed.get();
+        let size = PageSize::base()
+            .round_up(needed.max(committed.saturating_mul(2)))?
+            .min(self.max);
+
+        if size < needed {
+            return None;
+        }
+
+        self.commit(size).ok()
+    }
+
+    /// Makes the first `size` bytes accessible
+    ///
+    /// The pages are split off the reserved mapping and reprotected. If
+    /// that fails, the rest of the reservation is given up, since the
+    /// accessible pages must be contiguous.
+    fn commit(&self, size: usize) -> Result<(), Error<()>> {
+        let committed = self.committed.get();
+        if size <= committed {
+            return Ok(());
+        }
+
+        let mut parts = self.parts.borrow_mut();
+        let reserved = match parts.reserved.take() {
+            Some(reserved) => reserved,
+            None => return Err(ErrorKind::OutOfMemory.into()),
+        };
+
+        let head = match size - committed < reserved.size() {
+            false => reserved,
+            true => {
+                let (head, rest) = reserved
+                    .split(size - committed)
+                    .map_err(|e| e.map_inner(|reserved| parts.reserved = Some(reserved)))?;
+
+                parts.reserved = Some(rest);
+                head
+            }
+        };
+
+        let head = head.reprotect(perms::ReadWrite).map_err(|e| {
+            parts.reserved = None;
+            e.map_inner(drop)
+        })?;
+
+        parts.committed.push(head);
+        self.committed.set(size);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alloc() {
+        let psize = PageSize::base().bytes();
+        let mut arena = Arena::new(psize).unwrap();
+        assert_eq!(arena.capacity(), psize);
+
+        let a = arena.alloc(1u8).unwrap();
+        let b = arena.alloc(2u64).unwrap();
+        assert_eq!(b as *mut u64 as usize % align_of::<u64>(), 0);
+        assert_eq!((*a, *b), (1, 2));
+
+        let s = arena.alloc_slice(&[1u32, 2, 3]).unwrap();
+        assert_eq!(s, &[1, 2, 3]);
+        assert_eq!(arena.used(), 8 + 8 + 12);
+
+        assert!(arena.alloc_bytes(psize, 1).is_none());
+        assert!(arena.alloc_bytes(1, 3).is_none());
+
+        arena.reset();
+        assert_eq!(arena.used(), 0);
+        assert_eq!(arena.alloc_bytes(psize, 1).unwrap().len(), psize);
+    }
+
+    #[test]
+    fn grow() {
+        let psize = PageSize::base().bytes();
+        let mut arena = Arena::reserve(psize, psize * 16).unwrap();
+        assert_eq!(arena.max(), psize * 16);
+
+        let first = arena.alloc_bytes(psize, 1).unwrap();
+        let second = arena.alloc_bytes(psize * 2, 1).unwrap();
+        second[psize * 2 - 1] = 1;
+        first[0] = 1;
+        assert_eq!(arena.capacity(), psize * 3);
+
+        assert!(arena.alloc_bytes(psize * 14, 1).is_none());
+        assert!(arena.alloc_bytes(psize * 13, 1).is_some());
+        assert_eq!(arena.capacity(), psize * 16);
+
+        // The pages were made accessible through the mappings.
+        #[cfg(feature = "tracking")]
+        {
+            let end = arena.base + arena.max();
+            let records: Vec<_> = crate::tracking::snapshot()
+                .into_iter()
+                .filter(|r| r.addr >= arena.base && r.addr < end)
+                .collect();
+
+            let rw = libc::PROT_READ | libc::PROT_WRITE;
+            assert_eq!(records.iter().map(|r| r.size).sum::<usize>(), psize * 16);
+            assert!(records.iter().all(|r| r.prot == rw));
+        }
+
+        arena.reset();
+        assert_eq!(arena.capacity(), psize * 16);
+        assert!(Arena::reserve(psize * 2, psize).is_err());
+    }
+}
@@ -0,0 +1,487 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Backends performing the memory management system calls
+//!
+//! By default, mappings are created and destroyed by calling the system
+//! directly through `libc` (see `Libc`). Environments which cannot do this,
+//! such as a shim which forwards system calls to its host, can implement
+//! `Backend` and select it with `with_backend()` on the builder:
+//!
+//! ```rust
+//! use mmarinus::backend::Libc;
+//! use mmarinus::{Map, perms};
+//!
+//! let map: Map<perms::Read, _, Libc> = Map::bytes(4096)
+//!     .anywhere()
+//!     .anonymously()
+//!     .with_backend::<Libc>()
+//!     .with(perms::Read)
+//!     .unwrap();
+//! ```
+//!
+//! The backend is part of the type of the mapping, so that dropping the
+//! mapping unmaps it through the same backend that created it.
+
+use crate::errno;
+
+/// A provider of the memory management system calls
+///
+/// The functions have the semantics of the system calls of the same name,
+/// except that they report failures as errors rather than through `errno`.
+/// Backends are types rather than values, so any state they need must be
+/// global.
+pub trait Backend {
+    /// Whether the mappings of this backend are visible to the hooks
+    ///
+    /// If `false`, the observer (see the `observer` module) is not notified
+    /// of operations on the mappings and they are not recorded with the
+    /// `tracking` feature. The allocators in the `alloc` module use this,
+    /// since the hooks may allocate.
+    const HOOKS: bool = true;
+
+    /// Creates a mapping (`mmap()`) and returns its address
+    ///
+    /// # Safety
+    ///
+    /// With `MAP_FIXED`, this can replace existing mappings.
+    unsafe fn mmap(
+        addr: usize,
+        size: usize,
+        prot: libc::c_int,
+        flags: libc::c_int,
+        fd: libc::c_int,
+        offset: libc::off_t,
+    ) -> errno::Result<usize>;
+
+    /// Removes a mapping (`munmap()`)
+    ///
+    /// # Safety
+    ///
+    /// The memory must not be referenced after it has been unmapped.
+    unsafe fn munmap(addr: usize, size: usize) -> errno::Result<()>;
+
+    /// Changes the permissions of a mapping (`mprotect()`)
+    ///
+    /// # Safety
+    ///
+    /// The memory must not be referenced in ways the new permissions forbid.
+    unsafe fn mprotect(addr: usize, size: usize, prot: libc::c_int) -> errno::Result<()>;
+
+    /// Moves or resizes a mapping (`mremap()`) and returns its new address
+    ///
+    /// # Safety
+    ///
+    /// The memory must not be referenced at its old address after it moved.
+    unsafe fn mremap(
+        addr: usize,
+        size: usize,
+        new_size: usize,
+        flags: libc::c_int,
+        new_addr: usize,
+    ) -> errno::Result<usize>;
+
+    /// Advises the kernel about the use of a mapping (`madvise()`)
+    ///
+    /// # Safety
+    ///
+    /// Some advice (for example, `MADV_DONTNEED`) changes the contents of
+    /// the memory.
+    unsafe fn madvise(addr: usize, size: usize, advice: libc::c_int) -> errno::Result<()>;
+}
+
+/// The backend which calls the system through `libc`
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Libc;
+
+impl Backend for Libc {
+    #[inline]
+    unsafe fn mmap(
+        addr: usize,
+        size: usize,
+        prot: libc::c_int,
+        flags: libc::c_int,
+        fd: libc::c_int,
+        offset: libc::off_t,
+    ) -> errno::Result<usize> {
+        match libc::mmap(addr as _, size, prot, flags, fd, offset) {
+            libc::MAP_FAILED => Err(errno::last()),
+            ret => Ok(ret as usize),
+        }
+    }
+
+    #[inline]
+    unsafe fn munmap(addr: usize, size: usize) -> errno::Result<()> {
+        match libc::munmap(addr as _, size) {
+            0 => Ok(()),
+            _ => Err(errno::last()),
+        }
+    }
+
+    #[inline]
+    unsafe fn mprotect(addr: usize, size: usize, prot: libc::c_int) -> errno::Result<()> {
+        match libc::mprotect(addr as _, size, prot) {
+            0 => Ok(()),
+            _ => Err(errno::last()),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[inline]
+    unsafe fn mremap(
+        addr: usize,
+        size: usize,
+        new_size: usize,
+        flags: libc::c_int,
+        new_addr: usize,
+    ) -> errno::Result<usize> {
+        match libc::mremap(
+            addr as _,
+            size,
+            new_size,
+            flags,
+            new_addr as *mut libc::c_void,
+        ) {
+            libc::MAP_FAILED => Err(errno::last()),
+            ret => Ok(ret as usize),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    #[inline]
+    unsafe fn mremap(
+        _addr: usize,
+        _size: usize,
+        _new_size: usize,
+        _flags: libc::c_int,
+        _new_addr: usize,
+    ) -> errno::Result<usize> {
+        Err(errno::from_raw(libc::ENOSYS))
+    }
+
+    #[inline]
+    unsafe fn madvise(addr: usize, size: usize, advice: libc::c_int) -> errno::Result<()> {
+        match libc::madvise(addr as _, size, advice) {
+            0 => Ok(()),
+            _ => Err(errno::last()),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+pub use mock::{Call, Mock, Syscall};
+
+#[cfg(feature = "std")]
+mod mock {
+    use super::{Backend, Libc};
+    use crate::errno;
+
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    /// A system call made through the `Mock` backend
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    pub enum Syscall {
+        /// `mmap()`
+        Mmap,
+
+        /// `munmap()`
+        Munmap,
+
+        /// `mprotect()`
+        Mprotect,
+
+        /// `mremap()`
+        Mremap,
+
+        /// `madvise()`
+        Madvise,
+    }
+
+    /// A recorded call to the `Mock` backend
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    pub struct Call {
+        /// The system call
+        pub syscall: Syscall,
+
+        /// The address
+        pub addr: usize,
+
+        /// The size
+        pub size: usize,
+
+        /// The permissions (`mmap()`, `mprotect()`), the flags (`mremap()`)
+        /// or the advice (`madvise()`)
+        pub arg: libc::c_int,
+
+        /// The flags (`mmap()` only, otherwise zero)
+        pub flags: libc::c_int,
+
+        /// The error number, if the call failed
+        pub errno: Option<libc::c_int>,
+    }
+
+    #[derive(Default)]
+    struct State {
+        calls: Vec<Call>,
+        failures: VecDeque<(Syscall, libc::c_int)>,
+    }
+
+    thread_local! {
+        static STATE: RefCell<State> = RefCell::new(State::default());
+    }
+
+    /// A backend which records calls and injects failures
+    ///
+    /// Calls are forwarded to `Libc` unless a failure has been queued for
+    /// them with `Mock::fail()`. Both the recorded calls and the queued
+    /// failures are per thread, so tests using this backend can run in
+    /// parallel.
+    ///
+    /// ```rust
+    /// use mmarinus::backend::{Mock, Syscall};
+    /// use mmarinus::{Map, perms};
+    ///
+    /// let map = Map::bytes(4096)
+    ///     .anywhere()
+    ///     .anonymously()
+    ///     .with_backend::<Mock>()
+    ///     .with(perms::Read)
+    ///     .unwrap();
+    ///
+    /// Mock::fail(Syscall::Mprotect, libc::ENOMEM);
+    /// let map = map.reprotect(perms::ReadWrite).unwrap_err().map;
+    /// drop(map);
+    ///
+    /// let calls: Vec<_> = Mock::calls().iter().map(|c| c.syscall).collect();
+    /// assert_eq!(calls, [Syscall::Mmap, Syscall::Mprotect, Syscall::Munmap]);
+    /// ```
+    #[derive(Copy, Clone, Debug, Default)]
+    pub struct Mock;
+
+    impl Mock {
+        /// Queues a failure with `errno` for the next call of `syscall`
+        ///
+        /// Failures are consumed in the order in which they were queued.
+        pub fn fail(syscall: Syscall, errno: libc::c_int) {
+            STATE.with(|s| s.borrow_mut().failures.push_back((syscall, errno)));
+        }
+
+        /// Takes the calls recorded on this thread so far
+        pub fn calls() -> Vec<Call> {
+            STATE.with(|s| std::mem::take(&mut s.borrow_mut().calls))
+        }
+
+        /// Forgets the recorded calls and the queued failures of this thread
+        pub fn reset() {
+            STATE.with(|s| *s.borrow_mut() = State::default());
+        }
+
+        /// Records a call, performing it with `f` unless it should fail
+        fn call<T>(
+            syscall: Syscall,
+            addr: usize,
+            size: usize,
+            arg: libc::c_int,
+            flags: libc::c_int,
+            f: impl FnOnce() -> errno::Result<T>,
+        ) -> errno::Result<T> {
+            let failure = STATE.with(|s| {
+                let mut s = s.borrow_mut();
+                let index = s.failures.iter().position(|f| f.0 == syscall)?;
+                s.failures.remove(index).map(|f| f.1)
+            });
+
+            let ret = match failure {
+                Some(errno) => Err(errno::from_raw(errno)),
+                None => f(),
+            };
+
+            let errno = ret.as_ref().err().map(|e| e.raw_os_error().unwrap_or(0));
+            let call = Call {
+                syscall,
+                addr,
+                size,
+                arg,
+                flags,
+                errno,
+            };
+
+            STATE.with(|s| s.borrow_mut().calls.push(call));
+            ret
+        }
+    }
+
+    impl Backend for Mock {
+        unsafe fn mmap(
+            addr: usize,
+            size: usize,
+            prot: libc::c_int,
+            flags: libc::c_int,
+            fd: libc::c_int,
+            offset: libc::off_t,
+        ) -> errno::Result<usize> {
+            Self::call(Syscall::Mmap, addr, size, prot, flags, || {
+                Libc::mmap(addr, size, prot, flags, fd, offset)
+            })
+        }
+
+        unsafe fn munmap(addr: usize, size: usize) -> errno::Result<()> {
+            Self::call(Syscall::Munmap, addr, size, 0, 0, || {
+                Libc::munmap(addr, size)
+            })
+        }
+
+        unsafe fn mprotect(addr: usize, size: usize, prot: libc::c_int) -> errno::Result<()> {
+            Self::call(Syscall::Mprotect, addr, size, prot, 0, || {
+                Libc::mprotect(addr, size, prot)
+            })
+        }
+
+        unsafe fn mremap(
+            addr: usize,
+            size: usize,
+            new_size: usize,
+            flags: libc::c_int,
+            new_addr: usize,
+        ) -> errno::Result<usize> {
+            Self::call(Syscall::Mremap, addr, size, flags, 0, || {
+                Libc::mremap(addr, size, new_size, flags, new_addr)
+            })
+        }
+
+        unsafe fn madvise(addr: usize, size: usize, advice: libc::c_int) -> errno::Result<()> {
+            Self::call(Syscall::Madvise, addr, size, advice, 0, || {
+                Libc::madvise(addr, size, advice)
+            })
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{perms, Map};
+
+    fn syscalls() -> Vec<Syscall> {
+        Mock::calls().iter().map(|c| c.syscall).collect()
+    }
+
+    #[test]
+    fn reprotect() {
+        Mock::reset();
+
+        let mut map = Map::bytes(4096)
+            .anywhere()
+            .anonymously()
+            .with_backend::<Mock>()
+            .with(perms::ReadWrite)
+            .unwrap();
+
+        map[0] = 7;
+
+        Mock::fail(Syscall::Mprotect, libc::EACCES);
+        let err = map.reprotect(perms::Read).unwrap_err();
+        assert_eq!(err.err.raw_os_error(), Some(libc::EACCES));
+
+        // The mapping is returned unchanged and still writable.
+        let mut map = err.map;
+        map[0] += 1;
+        assert_eq!(map[0], 8);
+
+        let map = map.reprotect(perms::Read).unwrap();
+        let addr = map.addr();
+        drop(map);
+
+        let calls = Mock::calls();
+        assert_eq!(calls.len(), 4);
+        assert_eq!(calls[1].errno, Some(libc::EACCES));
+        assert_eq!(calls[2].arg, libc::PROT_READ);
+        assert_eq!(calls[2].errno, None);
+        assert_eq!((calls[3].syscall, calls[3].addr), (Syscall::Munmap, addr));
+    }
+
+    #[test]
+    fn remap() {
+        Mock::reset();
+
+        let map = Map::bytes(4096)
+            .anywhere()
+            .anonymously()
+            .with_backend::<Mock>()
+            .with(perms::Read)
+            .unwrap();
+
+        let addr = map.addr();
+
+        Mock::fail(Syscall::Mmap, libc::ENOMEM);
+        let err = map
+            .remap()
+            .anonymously()
+            .with_backend::<Mock>()
+            .with(perms::ReadWrite)
+            .unwrap_err();
+
+        assert_eq!(err.op().unwrap().addr, addr);
+        assert_eq!(err.map.addr(), addr);
+        assert_eq!(err.map.size(), 4096);
+        assert_eq!(syscalls(), [Syscall::Mmap, Syscall::Mmap]);
+
+        // The old mapping is unmapped only once.
+        drop(err);
+        assert_eq!(syscalls(), [Syscall::Munmap]);
+    }
+
+    #[test]
+    fn create() {
+        Mock::reset();
+
+        Mock::fail(Syscall::Mmap, libc::EAGAIN);
+        let err = Map::bytes(4096)
+            .anywhere()
+            .anonymously()
+            .with_backend::<Mock>()
+            .with(perms::Read)
+            .unwrap_err();
+
+        assert_eq!(err.err.raw_os_error(), Some(libc::EAGAIN));
+        assert_eq!(syscalls(), [Syscall::Mmap]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn fallback() {
+        Mock::reset();
+
+        // Fail the hugetlb mapping and the huge page advice.
+        Mock::fail(Syscall::Mmap, libc::ENOMEM);
+        Mock::fail(Syscall::Madvise, libc::EINVAL);
+        let map = Map::bytes(4096)
+            .anywhere()
+            .anonymously()
+            .with_huge_pages_or_fallback(0)
+            .with_backend::<Mock>()
+            .with(perms::Read)
+            .unwrap();
+
+        assert_eq!(map.tier(), crate::page::Tier::Base);
+        assert_eq!(map.addr() % (2 << 20), 0);
+        drop(map);
+
+        let calls = syscalls();
+        assert_eq!(calls[..3], [Syscall::Mmap; 3]);
+        assert_eq!(calls.last(), Some(&Syscall::Munmap));
+        assert!(calls.contains(&Syscall::Madvise));
+
+        // Other errors are not a reason to fall back.
+        Mock::fail(Syscall::Mmap, libc::EACCES);
+        let err = Map::bytes(4096)
+            .anywhere()
+            .anonymously()
+            .with_huge_pages_or_fallback(0)
+            .with_backend::<Mock>()
+            .with(perms::Read)
+            .unwrap_err();
+
+        assert_eq!(err.err.raw_os_error(), Some(libc::EACCES));
+        assert_eq!(syscalls(), [Syscall::Mmap]);
+    }
+}
@@ -1,14 +1,30 @@
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::map::{Kind, Private};
+use crate::backend::{Backend, Libc};
+use crate::kinds::Private;
+use crate::map::Kind;
+#[cfg(all(target_os = "linux", feature = "std"))]
+use crate::numa::{self, BindFlags, Policy};
+#[cfg(all(target_os = "linux", feature = "std"))]
+use crate::page::HugePageSize;
+use crate::page::{PageSize, Tier};
+#[cfg(all(target_os = "linux", feature = "std"))]
+use crate::prefault::{MADV_POPULATE_READ, MADV_POPULATE_WRITE};
+#[cfg(feature = "tracking")]
+use crate::tracking::{self, Record};
 
+use super::errno;
+use super::error::{Op, Operation};
 use super::map::Type;
-use super::{Error, Map};
+use super::observer::{self, Event};
+use super::{Error, Flags, Map};
 
-use std::io::ErrorKind;
-use std::marker::PhantomData;
-use std::mem::forget;
-use std::os::unix::io::{AsRawFd, RawFd};
+use core::cell::Cell;
+use core::marker::PhantomData;
+use core::mem::forget;
+
+#[cfg(feature = "std")]
+use std::os::unix::io::AsRawFd;
 
 pub trait Stage {}
 
@@ -30,17 +46,27 @@ pub struct Destination<M> {
     pub(crate) addr: Address,
 }
 
-pub struct Source<M, K: Kind> {
+pub struct Source<M, K: Kind, B: Backend = Libc> {
     prev: Destination<M>,
-    fd: RawFd,
+    fd: libc::c_int,
     offset: libc::off_t,
     huge: Option<i32>,
+    #[cfg(all(target_os = "linux", feature = "std"))]
+    pages: Option<HugePageSize>,
+    #[cfg(target_os = "linux")]
+    fallback: bool,
+    #[cfg(all(target_os = "linux", feature = "std"))]
+    numa: Option<(Vec<u32>, Policy)>,
+    flags: Flags,
     kind: K,
+    #[cfg(feature = "tracking")]
+    label: Option<&'static str>,
+    backend: PhantomData<B>,
 }
 
 impl<M> Stage for Size<M> {}
 impl<M> Stage for Destination<M> {}
-impl<M, K: Kind> Stage for Source<M, K> {}
+impl<M, K: Kind, B: Backend> Stage for Source<M, K, B> {}
 
 /// A builder used to construct a new memory mapping
 pub struct Builder<S: Stage>(pub(crate) S);
@@ -108,27 +134,57 @@ impl<M> Builder<Destination<M>> {
             kind: Private,
             prev: self.0,
             huge: None,
+            #[cfg(all(target_os = "linux", feature = "std"))]
+            pages: None,
+            #[cfg(target_os = "linux")]
+            fallback: false,
+            #[cfg(all(target_os = "linux", feature = "std"))]
+            numa: None,
+            flags: Flags::empty(),
             offset: 0,
             fd: -1,
+            #[cfg(feature = "tracking")]
+            label: None,
+            backend: PhantomData,
         })
     }
 
     /// Creates the mapping using the contents of the specified file
     ///
     /// This is equivalent to specifying a valid file descriptor and an offset.
+    #[cfg(feature = "std")]
     #[inline]
     pub fn from<U: AsRawFd>(self, file: &mut U, offset: i64) -> Builder<Source<M, Private>> {
+        self.fd(file.as_raw_fd(), offset)
+    }
+
+    /// Creates the mapping using the contents of the specified file descriptor
+    ///
+    /// This is like `from()`, but it is available without the `std` feature.
+    /// The file descriptor only needs to stay open until the mapping is created.
+    #[inline]
+    pub fn fd(self, fd: libc::c_int, offset: i64) -> Builder<Source<M, Private>> {
         Builder(Source {
-            fd: file.as_raw_fd(),
+            fd,
             kind: Private,
             prev: self.0,
             huge: None,
+            #[cfg(all(target_os = "linux", feature = "std"))]
+            pages: None,
+            #[cfg(target_os = "linux")]
+            fallback: false,
+            #[cfg(all(target_os = "linux", feature = "std"))]
+            numa: None,
+            flags: Flags::empty(),
             offset,
+            #[cfg(feature = "tracking")]
+            label: None,
+            backend: PhantomData,
         })
     }
 }
 
-impl<M, K: Kind> Builder<Source<M, K>> {
+impl<M, K: Kind, B: Backend> Builder<Source<M, K, B>> {
     /// Uses huge pages for the mapping
     ///
     /// If `pow = 0`, the kernel will pick the huge page size. Otherwise, if
@@ -138,21 +194,163 @@ impl<M, K: Kind> Builder<Source<M, K>> {
     /// macOS (10.7 up through the present 15.0) supports "superpages", but
     /// only in one size: 2MB. So the kernel will pick the huge page size for
     /// all values of `pow`, and it always chooses 2MB.
+    ///
+    /// On Linux, the size of the mapping is rounded up to a multiple of the
+    /// huge page size. See `Map::page_size()`.
     #[inline]
     pub fn with_huge_pages(mut self, pow: u8) -> Self {
         self.0.huge = Some(pow.into());
+        #[cfg(all(target_os = "linux", feature = "std"))]
+        {
+            self.0.pages = None;
+        }
+        #[cfg(target_os = "linux")]
+        {
+            self.0.fallback = false;
+        }
+        self
+    }
+
+    /// Uses huge pages for the mapping, if they are available
+    ///
+    /// This is like `with_huge_pages()`, but if the huge pages cannot be
+    /// allocated, the mapping is created from base pages instead. These are
+    /// aligned to 2MB (when no address is given) and advised with
+    /// `MADV_HUGEPAGE` so that the kernel may back them with transparent
+    /// huge pages. If the kernel refuses the advice, plain base pages are
+    /// used. Use `Map::tier()` to find out which kind of pages were used.
+    ///
+    /// Only `ENOMEM` and `EINVAL` (the errors for missing or unsupported
+    /// huge pages) lead to the fallback; other errors are returned as is.
+    ///
+    /// ```rust
+    /// use mmarinus::{Map, perms, page::Tier};
+    ///
+    /// let map = Map::bytes(4 << 20)
+    ///     .anywhere()
+    ///     .anonymously()
+    ///     .with_huge_pages_or_fallback(0)
+    ///     .with(perms::ReadWrite)
+    ///     .unwrap();
+    ///
+    /// match map.tier() {
+    ///     Tier::Huge => assert!(map.page_size().bytes() >= 2 << 20),
+    ///     Tier::Transparent => assert_eq!(map.addr() % (2 << 20), 0),
+    ///     Tier::Base => (),
+    /// }
+    /// ```
+    #[cfg(target_os = "linux")]
+    #[inline]
+    pub fn with_huge_pages_or_fallback(mut self, pow: u8) -> Self {
+        self.0.huge = Some(pow.into());
+        #[cfg(feature = "std")]
+        {
+            self.0.pages = None;
+        }
+        self.0.fallback = true;
+        self
+    }
+
+    /// Uses huge pages of the specified size for the mapping
+    ///
+    /// Unlike `with_huge_pages()`, the size is validated against the huge
+    /// page sizes supported by the system (see `PageSize::huge()`) before
+    /// the mapping is created. If the system does not support the size, the
+    /// mapping fails with `ErrorKind::InvalidInput`. If too few huge pages
+    /// of that size are reserved, it fails with `ErrorKind::OutOfMemory`.
+    #[cfg(all(target_os = "linux", feature = "std"))]
+    #[inline]
+    pub fn with_huge_page_size(mut self, size: HugePageSize) -> Self {
+        self.0.huge = None;
+        self.0.pages = Some(size);
+        self.0.fallback = false;
+        self
+    }
+
+    /// Uses additional flags for map creation
+    ///
+    /// The flags are validated against the source of the mapping when the
+    /// mapping is created. For example, `Flags::GROWSDOWN` is rejected for
+    /// file-backed mappings and `Flags::SYNC` is rejected for anything other
+    /// than a file-backed shared mapping.
+    ///
+    /// Calling this function more than once accumulates the flags.
+    #[inline]
+    pub fn with_flags(mut self, flags: Flags) -> Self {
+        self.0.flags |= flags;
+        self
+    }
+
+    /// Uses the specified NUMA memory policy for the mapping
+    ///
+    /// The policy is applied (see `Map::bind()`) before any pages of the
+    /// mapping are faulted in. If `Flags::POPULATE` is used, the pages are
+    /// populated only after the policy has been applied.
+    ///
+    /// When replacing a mapping (e.g. with `Map::remap()`), the new mapping
+    /// is created elsewhere and only moved over the old one once the policy
+    /// has been applied, so the old mapping is returned intact on failure.
+    #[cfg(all(target_os = "linux", feature = "std"))]
+    #[inline]
+    pub fn with_numa_policy(mut self, nodes: &[u32], policy: Policy) -> Self {
+        self.0.numa = Some((nodes.to_vec(), policy));
+        self
+    }
+
+    /// Labels the mapping in the registry of live mappings
+    ///
+    /// The label shows up in the records of `tracking::snapshot()`.
+    #[cfg(feature = "tracking")]
+    #[inline]
+    pub fn with_label(mut self, label: &'static str) -> Self {
+        self.0.label = Some(label);
         self
     }
 
     /// Uses the specified map kind for map creation
     #[inline]
-    pub fn with_kind<X: Kind>(self, kind: X) -> Builder<Source<M, X>> {
+    pub fn with_kind<X: Kind>(self, kind: X) -> Builder<Source<M, X, B>> {
         Builder(Source {
             offset: self.0.offset,
             prev: self.0.prev,
             huge: self.0.huge,
+            #[cfg(all(target_os = "linux", feature = "std"))]
+            pages: self.0.pages,
+            #[cfg(target_os = "linux")]
+            fallback: self.0.fallback,
+            #[cfg(all(target_os = "linux", feature = "std"))]
+            numa: self.0.numa,
+            flags: self.0.flags,
             fd: self.0.fd,
             kind,
+            #[cfg(feature = "tracking")]
+            label: self.0.label,
+            backend: PhantomData,
+        })
+    }
+
+    /// Uses the specified backend for the system calls of the mapping
+    ///
+    /// The backend is used both to create the mapping and for all later
+    /// operations on it, including unmapping it when it is dropped.
+    #[inline]
+    pub fn with_backend<X: Backend>(self) -> Builder<Source<M, K, X>> {
+        Builder(Source {
+            offset: self.0.offset,
+            prev: self.0.prev,
+            huge: self.0.huge,
+            #[cfg(all(target_os = "linux", feature = "std"))]
+            pages: self.0.pages,
+            #[cfg(target_os = "linux")]
+            fallback: self.0.fallback,
+            #[cfg(all(target_os = "linux", feature = "std"))]
+            numa: self.0.numa,
+            flags: self.0.flags,
+            fd: self.0.fd,
+            kind: self.0.kind,
+            #[cfg(feature = "tracking")]
+            label: self.0.label,
+            backend: PhantomData,
         })
     }
 
@@ -162,94 +360,427 @@ impl<M, K: Kind> Builder<Source<M, K>> {
     /// `Unknown` (i.e. runtime) permissions as this will supply a variety of
     /// useful APIs.
     #[inline]
-    pub fn with<T: Type>(self, perms: T) -> Result<Map<T, K>, Error<M>> {
-        let einval = ErrorKind::InvalidInput.into();
+    pub fn with<T: Type>(self, perms: T) -> Result<Map<T, K, B>, Error<M>> {
         let perms = perms.perms();
-        let kind = self.0.kind.kind();
+        let Destination { prev, addr } = self.0.prev;
+
+        // Populating the mapping would fault in the pages before the
+        // policy is applied, so populate it afterwards instead.
+        #[cfg(all(target_os = "linux", feature = "std"))]
+        let (flags, populate) = match self.0.numa {
+            Some(..) => (
+                self.0.flags.without(Flags::POPULATE),
+                self.0.flags.contains(Flags::POPULATE),
+            ),
+            None => (self.0.flags, false),
+        };
+
+        #[cfg(not(all(target_os = "linux", feature = "std")))]
+        let flags = self.0.flags;
+
+        let req = Request {
+            addr,
+            size: prev.size,
+            fd: self.0.fd,
+            offset: self.0.offset,
+            huge: self.0.huge,
+            #[cfg(all(target_os = "linux", feature = "std"))]
+            pages: self.0.pages,
+            flags,
+            kind: self.0.kind.kind(),
+            #[cfg(all(target_os = "linux", feature = "std"))]
+            staged: self.0.numa.is_some(),
+            attempt: Cell::new(0),
+        };
+
+        #[cfg(feature = "tracking")]
+        let onto = match req.addr {
+            Address::Onto(addr) => Some(addr),
+            _ => None,
+        };
+
+        #[cfg(feature = "tracking")]
+        if B::HOOKS && !tracking::reserve(req.size, onto) {
+            let err = errno::from_raw(libc::EDQUOT);
+            req.observe::<B>(perms, Err(&err));
+            return Err(req.error(prev.prev, err, Op::Map, perms));
+        }
+
+        let huge = req.huge.is_some();
+
+        #[cfg(all(target_os = "linux", feature = "std"))]
+        let huge = huge || req.pages.is_some();
 
         #[cfg(target_os = "linux")]
-        let huge = match self.0.huge {
-            Some(x) if x & !libc::MAP_HUGE_MASK != 0 => {
-                return Err(Error {
-                    map: self.0.prev.prev.prev,
-                    err: einval,
+        let ret = match self.0.fallback {
+            false => req.mmap::<B>(perms, huge),
+            true => match req.mmap::<B>(perms, true) {
+                Err(err) if unavailable(&err) => match req.transparent::<B>(perms) {
+                    Err(err) if unavailable(&err) => req.mmap::<B>(perms, false),
+                    ret => ret,
+                },
+
+                ret => ret,
+            },
+        };
+
+        #[cfg(not(target_os = "linux"))]
+        let ret = req.mmap::<B>(perms, huge);
+
+        // The policy is applied before the mapping replaces the one at the
+        // address (if any), which therefore survives a failure.
+        #[cfg(all(target_os = "linux", feature = "std"))]
+        let ret = match (ret, &self.0.numa) {
+            (Ok(m), Some((nodes, policy))) => {
+                match numa::mbind(m.0, m.1, nodes, *policy, BindFlags::empty()) {
+                    Err(err) => {
+                        let _ = unsafe { B::munmap(m.0, m.1) };
+                        Err((err.into(), Op::Bind))
+                    }
+
+                    Ok(()) => match req.place::<B>(m) {
+                        Err(err) => Err((err, Op::Remap)),
+                        Ok(m) => {
+                            if populate {
+                                let advice = match perms & libc::PROT_WRITE {
+                                    0 => MADV_POPULATE_READ,
+                                    _ => MADV_POPULATE_WRITE,
+                                };
+
+                                // Like MAP_POPULATE, this is only a hint.
+                                let _ = unsafe { B::madvise(m.0, m.1, advice) };
+                            }
+
+                            Ok(m)
+                        }
+                    },
+                }
+            }
+
+            (ret, _) => ret.map_err(|err| (err, Op::Map)),
+        };
+
+        #[cfg(not(all(target_os = "linux", feature = "std")))]
+        let ret = ret.map_err(|err| (err, Op::Map));
+
+        #[cfg(feature = "tracking")]
+        match ret {
+            _ if !B::HOOKS => (),
+            Err(..) => tracking::release(req.size),
+            Ok((addr, size, ..)) => tracking::commit(
+                req.size,
+                Record {
+                    addr,
+                    size,
+                    prot: perms,
+                    kind: req.kind,
+                    label: self.0.label,
+                },
+            ),
+        }
+
+        req.observe::<B>(perms, ret.as_ref().map(|m| (m.0, m.1)).map_err(|e| &e.0));
+
+        match ret {
+            Ok((addr, size, page, tier)) => {
+                forget(prev.prev);
+                Ok(Map {
+                    addr,
+                    size,
+                    page,
+                    tier,
+                    data: PhantomData,
                 })
             }
 
-            Some(x) => (x << libc::MAP_HUGE_SHIFT) | libc::MAP_HUGETLB,
-            None => 0,
+            Err((err, op)) => Err(req.error(prev.prev, err, op, perms)),
+        }
+    }
+}
+
+/// Indicates whether an attempt failed for lack of (huge) pages
+///
+/// Only then does `Builder::with_huge_pages_or_fallback()` try the next
+/// kind of pages; all other errors are returned as they are.
+#[cfg(target_os = "linux")]
+fn unavailable(err: &errno::OsError) -> bool {
+    matches!(err.raw_os_error(), Some(libc::ENOMEM) | Some(libc::EINVAL))
+}
+
+/// The parameters of a mapping, independent of the builder types
+struct Request {
+    addr: Address,
+    size: usize,
+    fd: libc::c_int,
+    offset: libc::off_t,
+    huge: Option<i32>,
+    #[cfg(all(target_os = "linux", feature = "std"))]
+    pages: Option<HugePageSize>,
+    flags: Flags,
+    kind: libc::c_int,
+    /// Whether the mapping is created elsewhere and then moved onto the
+    /// address, so that the mapping it replaces survives a failure
+    #[cfg(all(target_os = "linux", feature = "std"))]
+    staged: bool,
+    attempt: Cell<libc::c_int>,
+}
+
+type Mapped = (usize, usize, PageSize, Tier);
+
+impl Request {
+    /// Creates the mapping, using huge pages if `huge` is set
+    fn mmap<B: Backend>(&self, perms: libc::c_int, huge: bool) -> errno::Result<Mapped> {
+        let einval = || errno::from_raw(libc::EINVAL);
+        let size = self.size;
+
+        #[cfg(target_os = "linux")]
+        self.attempt.set(match huge {
+            true => libc::MAP_HUGETLB,
+            false => 0,
+        });
+
+        #[cfg(all(target_os = "linux", feature = "std"))]
+        let huge = match (huge, self.pages) {
+            (false, _) => None,
+            (true, Some(p)) => Some(p.validate(size)?.shift().into()),
+            (true, None) => Some(self.huge.unwrap_or(0)),
+        };
+
+        #[cfg(all(target_os = "linux", not(feature = "std")))]
+        let huge = match huge {
+            false => None,
+            true => Some(self.huge.unwrap_or(0)),
+        };
+
+        // Hugetlb mappings can only be split or unmapped at huge page
+        // boundaries, so remember the size of the pages.
+        #[cfg(target_os = "linux")]
+        let page = match huge {
+            Some(x) if x & !libc::MAP_HUGE_MASK != 0 => None,
+            Some(0) => match PageSize::default_huge() {
+                // The system does not support huge pages.
+                Err(err) if err.raw_os_error() == Some(libc::ENOENT) => return Err(einval()),
+                ret => Some(ret?),
+            },
+            Some(x) => 1usize.checked_shl(x as u32).and_then(PageSize::new),
+            None => Some(PageSize::base()),
+        };
+
+        #[cfg(not(target_os = "linux"))]
+        let page = Some(PageSize::base());
+
+        // Only hugetlb mappings are rounded; the kernel rounds everything else.
+        #[cfg(target_os = "linux")]
+        let size = match huge {
+            Some(..) => page.and_then(|p| p.round_up(size)),
+            None => Some(size),
+        };
+
+        #[cfg(not(target_os = "linux"))]
+        let size = Some(size);
+
+        let (page, size) = page.zip(size).ok_or_else(einval)?;
+
+        #[cfg(target_os = "linux")]
+        let (huge, tier) = match huge {
+            Some(x) => ((x << libc::MAP_HUGE_SHIFT) | libc::MAP_HUGETLB, Tier::Huge),
+            None => (0, Tier::Base),
         };
 
         #[cfg(target_os = "macos")]
         #[allow(deprecated)]
-        let huge = match self.0.huge {
+        let (huge, tier) = match (huge, self.huge) {
             // The value is correct, but libc says "use the mach crate instead".
             // VM_FLAGS_SUPERPAGE_SIZE_2MB is in include/mach/vm_statistics.h
             // but mach::vm_statistics doesn't have it. So.. just use libc.
-            Some(21) => libc::VM_FLAGS_SUPERPAGE_SIZE_2MB,
-            Some(_) => libc::VM_FLAGS_SUPERPAGE_SIZE_ANY,
-            None => 0,
+            (true, Some(21)) => (libc::VM_FLAGS_SUPERPAGE_SIZE_2MB, Tier::Huge),
+            (true, Some(_)) => (libc::VM_FLAGS_SUPERPAGE_SIZE_ANY, Tier::Huge),
+            _ => (0, Tier::Base),
         };
 
-        let (addr, fixed) = match self.0.prev.addr {
+        let (addr, flags) = self.flags()?;
+
+        #[cfg(target_os = "linux")]
+        let flags = flags | huge;
+
+        self.attempt.set(flags);
+
+        #[cfg(target_os = "linux")]
+        let ret = unsafe { B::mmap(addr, size, perms, flags, self.fd, self.offset)? };
+
+        #[cfg(target_os = "macos")]
+        let ret = unsafe { B::mmap(addr, size, perms, flags, self.fd | huge, self.offset)? };
+
+        Ok((ret, size, page, tier))
+    }
+
+    /// Moves a staged mapping onto its address
+    ///
+    /// On failure, the staged mapping is removed. The kernel may remove the
+    /// mapping at the address before it fails to move the staged one; as
+    /// its owner would then unmap whatever is mapped there later, this
+    /// aborts the process.
+    #[cfg(all(target_os = "linux", feature = "std"))]
+    fn place<B: Backend>(&self, m: Mapped) -> errno::Result<Mapped> {
+        let addr = match self.addr {
+            Address::Onto(addr) if self.staged => addr,
+            _ => return Ok(m),
+        };
+
+        let flags = libc::MREMAP_MAYMOVE | libc::MREMAP_FIXED;
+        self.attempt.set(flags);
+        match unsafe { B::mremap(m.0, m.1, m.1, flags, addr) } {
+            Ok(addr) => Ok((addr, m.1, m.2, m.3)),
+            Err(err) => {
+                let _ = unsafe { B::munmap(m.0, m.1) };
+                if unsafe { libc::msync(addr as _, 1, libc::MS_ASYNC) } != 0 {
+                    std::process::abort();
+                }
+
+                Err(err)
+            }
+        }
+    }
+
+    /// Creates the mapping aligned for transparent huge pages, if possible
+    ///
+    /// If the kernel refuses `MADV_HUGEPAGE`, the mapping uses base pages.
+    #[cfg(target_os = "linux")]
+    fn transparent<B: Backend>(&self, perms: libc::c_int) -> errno::Result<Mapped> {
+        const ALIGN: usize = 2 << 20;
+
+        let size = self.size;
+        self.attempt.set(0);
+        let (addr, flags) = self.flags()?;
+        self.attempt.set(flags);
+
+        // Without an address, reserve enough space to align the mapping.
+        let ret = match (addr, size.checked_add(ALIGN)) {
+            (0, Some(reserve)) => unsafe {
+                let none = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE;
+                let start = B::mmap(addr, reserve, libc::PROT_NONE, none, -1, 0)?;
+                let aligned = (start + ALIGN - 1) & !(ALIGN - 1);
+                let fixed = flags | libc::MAP_FIXED;
+
+                match B::mmap(aligned, size, perms, fixed, self.fd, self.offset) {
+                    Err(err) => {
+                        let _ = B::munmap(start, reserve);
+                        return Err(err);
+                    }
+
+                    Ok(map) => {
+                        let end = match PageSize::base().round_up(size) {
+                            Some(len) => aligned + len,
+                            None => {
+                                let _ = B::munmap(start, reserve);
+                                return Err(errno::from_raw(libc::EINVAL));
+                            }
+                        };
+
+                        let _ = B::munmap(start, aligned - start);
+                        let _ = B::munmap(end, start + reserve - end);
+                        map
+                    }
+                }
+            },
+
+            _ => unsafe { B::mmap(addr, size, perms, flags, self.fd, self.offset)? },
+        };
+
+        let tier = match unsafe { B::madvise(ret, size, libc::MADV_HUGEPAGE) } {
+            Ok(()) => Tier::Transparent,
+            Err(..) => Tier::Base,
+        };
+
+        Ok((ret, size, PageSize::base(), tier))
+    }
+
+    /// Gets the requested address
+    fn address(&self) -> usize {
+        match self.addr {
+            Address::None => 0,
+            #[cfg(not(target_os = "macos"))]
+            Address::At(a) => a,
+            Address::Near(a) | Address::Onto(a) => a,
+        }
+    }
+
+    /// Reports the outcome of the request to the observer, if any
+    fn observe<B: Backend>(&self, prot: libc::c_int, ret: Result<(usize, usize), &errno::OsError>) {
+        let observer = match observer::get::<B>() {
+            Some(observer) => observer,
+            None => return,
+        };
+
+        let (addr, size, err) = match ret {
+            Ok((addr, size)) => (addr, size, None),
+            Err(err) => (self.address(), self.size, Some(err)),
+        };
+
+        let event = Event {
+            addr,
+            size,
+            prot: Some(prot),
+            kind: Some(self.kind),
+            err,
+        };
+
+        match self.addr {
+            Address::Onto(..) => observer.on_remap(&event),
+            _ => observer.on_map(&event),
+        }
+    }
+
+    /// Creates the error for a failed attempt to create the mapping
+    fn error<M>(&self, map: M, err: errno::OsError, op: Op, prot: libc::c_int) -> Error<M> {
+        Error {
+            map,
+            err: err.with(Operation {
+                op,
+                addr: self.address(),
+                size: self.size,
+                prot,
+                flags: self.attempt.get(),
+            }),
+        }
+    }
+
+    /// Gets the address and the flags (other than for huge pages) for `mmap()`
+    fn flags(&self) -> errno::Result<(usize, libc::c_int)> {
+        let einval = || errno::from_raw(libc::EINVAL);
+        let (addr, fixed) = match self.addr {
             Address::None => (0, 0),
             #[cfg(not(target_os = "macos"))]
             Address::At(a) if a != 0 => (a, libc::MAP_FIXED_NOREPLACE),
             Address::Near(a) if a != 0 => (a, 0),
+            #[cfg(all(target_os = "linux", feature = "std"))]
+            Address::Onto(a) if a != 0 && self.staged => (0, 0),
             Address::Onto(a) if a != 0 => (a, libc::MAP_FIXED),
-            _ => {
-                return Err(Error {
-                    map: self.0.prev.prev.prev,
-                    err: einval,
-                })
-            }
+            _ => return Err(einval()),
         };
 
-        let anon = match self.0.fd {
+        let anon = match self.fd {
             -1 => libc::MAP_ANONYMOUS,
             _ => 0,
         };
 
-        let size = self.0.prev.prev.size;
-        let flags = kind | fixed | anon;
-
+        // Reject flags which do not apply to the source of the mapping. Note
+        // that MAP_SYNC is silently ignored unless used with MAP_SHARED_VALIDATE.
         #[cfg(target_os = "linux")]
-        let ret = unsafe {
-            libc::mmap(
-                addr as _,
-                size,
-                perms,
-                flags | huge,
-                self.0.fd,
-                self.0.offset,
-            )
-        };
+        let kind = match (self.flags, anon, self.kind) {
+            (f, 0, _) if f.intersects(Flags::ANONYMOUS_ONLY) => return Err(einval()),
 
-        #[cfg(target_os = "macos")]
-        let ret = unsafe {
-            libc::mmap(
-                addr as _,
-                size,
-                perms,
-                flags,
-                self.0.fd | huge,
-                self.0.offset,
-            )
-        };
-
-        if ret == libc::MAP_FAILED {
-            return Err(Error {
-                map: self.0.prev.prev.prev,
-                err: std::io::Error::last_os_error(),
-            });
-        }
+            (f, 0, libc::MAP_SHARED | libc::MAP_SHARED_VALIDATE) if f.contains(Flags::SYNC) => {
+                libc::MAP_SHARED_VALIDATE
+            }
 
-        forget(self.0.prev.prev.prev);
+            (f, _, _) if f.contains(Flags::SYNC) => return Err(einval()),
+            (_, _, kind) => kind,
+        };
 
-        Ok(Map {
-            addr: ret as usize,
-            size: self.0.prev.prev.size,
-            data: PhantomData,
-        })
+        #[cfg(not(target_os = "linux"))]
+        let kind = self.kind;
+
+        Ok((addr, kind | fixed | anon | self.flags.bits()))
     }
 }
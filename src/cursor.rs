@@ -0,0 +1,244 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use super::backend::{Backend, Libc};
+use super::map::{copy_in, copy_out, Kind, Readable, Safe, Type, Writeable};
+use super::{perms, Map, Private};
+
+use std::convert::TryFrom;
+use std::io::{BufRead, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::slice::from_raw_parts;
+
+/// A cursor over a borrowed mapping
+///
+/// This implements the `std::io` traits on top of a mapping, similar to
+/// `std::io::Cursor`. `Read` and `Seek` are implemented for all readable
+/// mappings and `Write` for all writable ones. Since the data is copied
+/// with volatile reads and writes (like `Map::copy_to()` and
+/// `Map::copy_from()`), this works for all kinds of mappings, including
+/// `Shared` ones. `BufRead` is only implemented for kinds which can be
+/// referenced directly (i.e. `Private`).
+///
+/// A cursor created with `MapCursor::new()` borrows the mapping mutably.
+/// To read from a mapping which is only borrowed immutably, use
+/// `MapCursor::reader()`, whose cursor only has `perms::Read`.
+///
+/// Writing past the end of the mapping writes nothing; the mapping is
+/// never grown.
+///
+/// ```rust
+/// use mmarinus::{Map, MapCursor, Shared, perms};
+/// use std::io::{Read, Seek, SeekFrom, Write};
+///
+/// let mut map = Map::bytes(16)
+///     .anywhere()
+///     .anonymously()
+///     .with_kind(Shared)
+///     .with(perms::ReadWrite)
+///     .unwrap();
+///
+/// let mut cursor = MapCursor::new(&mut map);
+/// cursor.write_all(b"hello").unwrap();
+///
+/// let mut cursor = MapCursor::reader(&map);
+/// cursor.seek(SeekFrom::Start(1)).unwrap();
+///
+/// let mut buf = [0u8; 4];
+/// cursor.read_exact(&mut buf).unwrap();
+/// assert_eq!(&buf, b"ello");
+/// ```
+#[derive(Debug)]
+pub struct MapCursor<'a, T: Type, K: Kind = Private, B: Backend = Libc> {
+    addr: usize,
+    size: usize,
+    pos: u64,
+    map: PhantomData<&'a mut Map<T, K, B>>,
+}
+
+impl<'a, T: Type, K: Kind, B: Backend> MapCursor<'a, T, K, B> {
+    /// Creates a new cursor positioned at the start of the mapping
+    #[inline]
+    pub fn new(map: &'a mut Map<T, K, B>) -> Self {
+        Self {
+            addr: map.addr,
+            size: map.size,
+            pos: 0,
+            map: PhantomData,
+        }
+    }
+
+    /// Gets the current position of the cursor
+    #[inline]
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// Sets the current position of the cursor
+    #[inline]
+    pub fn set_position(&mut self, pos: u64) {
+        self.pos = pos;
+    }
+
+    /// Gets the range of the mapping which starts at the current position
+    ///
+    /// Returns `None` at or past the end of the mapping.
+    fn rest(&self) -> Option<(usize, usize)> {
+        match usize::try_from(self.pos) {
+            Ok(start) if start < self.size => Some((self.addr + start, self.size - start)),
+            _ => None,
+        }
+    }
+}
+
+impl<'a, K: Kind, B: Backend> MapCursor<'a, perms::Read, K, B> {
+    /// Creates a new read-only cursor positioned at the start of the mapping
+    #[inline]
+    pub fn reader<T: Readable>(map: &'a Map<T, K, B>) -> Self {
+        Self {
+            addr: map.addr,
+            size: map.size,
+            pos: 0,
+            map: PhantomData,
+        }
+    }
+}
+
+impl<'a, T: Readable, K: Kind, B: Backend> Read for MapCursor<'a, T, K, B> {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let (addr, size) = match self.rest() {
+            Some(rest) => rest,
+            None => return Ok(0),
+        };
+
+        let len = buf.len().min(size);
+        unsafe { copy_out(&mut buf[..len], addr as *const u8) };
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<'a, T: Readable, K: Safe, B: Backend> BufRead for MapCursor<'a, T, K, B> {
+    #[inline]
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        Ok(match self.rest() {
+            Some((addr, size)) => unsafe { from_raw_parts(addr as *const u8, size) },
+            None => &[],
+        })
+    }
+
+    #[inline]
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt as u64;
+    }
+}
+
+impl<'a, T: Writeable, K: Kind, B: Backend> Write for MapCursor<'a, T, K, B> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let (addr, size) = match self.rest() {
+            Some(rest) => rest,
+            None => return Ok(0),
+        };
+
+        let len = buf.len().min(size);
+        unsafe { copy_in(addr as *mut u8, &buf[..len]) };
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a, T: Type, K: Kind, B: Backend> Seek for MapCursor<'a, T, K, B> {
+    fn seek(&mut self, style: SeekFrom) -> std::io::Result<u64> {
+        let (base, offset) = match style {
+            SeekFrom::Start(n) => {
+                self.pos = n;
+                return Ok(n);
+            }
+            SeekFrom::End(n) => (self.size as u64, n),
+            SeekFrom::Current(n) => (self.pos, n),
+        };
+
+        let next = match offset >= 0 {
+            true => base.checked_add(offset as u64),
+            false => base.checked_sub(offset.wrapping_neg() as u64),
+        };
+
+        match next {
+            Some(n) => {
+                self.pos = n;
+                Ok(n)
+            }
+            None => Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{perms, Shared};
+
+    #[test]
+    fn private() {
+        let mut map = Map::bytes(8)
+            .anywhere()
+            .anonymously()
+            .with(perms::ReadWrite)
+            .unwrap();
+
+        let mut cursor = MapCursor::new(&mut map);
+        assert_eq!(cursor.write(b"0123456789").unwrap(), 8);
+        assert_eq!(cursor.write(b"89").unwrap(), 0);
+        assert_eq!(cursor.seek(SeekFrom::End(-3)).unwrap(), 5);
+        assert_eq!(cursor.fill_buf().unwrap(), b"567");
+        cursor.consume(1);
+
+        let mut rest = Vec::new();
+        cursor.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"67");
+
+        assert!(cursor.seek(SeekFrom::Current(-9)).is_err());
+        assert_eq!(cursor.position(), 8);
+        assert_eq!(&*map, b"01234567");
+
+        // Other readers may borrow the mapping at the same time.
+        let mut cursor = MapCursor::reader(&map);
+        let slice: &[u8] = &map;
+        assert_eq!(cursor.fill_buf().unwrap(), slice);
+    }
+
+    #[test]
+    fn shared() {
+        let mut map = Map::bytes(8)
+            .anywhere()
+            .anonymously()
+            .with_kind(Shared)
+            .with(perms::ReadWrite)
+            .unwrap();
+
+        let mut cursor = MapCursor::new(&mut map);
+        cursor.write_all(b"abcdefgh").unwrap();
+        assert!(cursor.write_all(b"i").is_err());
+
+        // Past the end, the position is left alone.
+        cursor.set_position(100);
+        assert_eq!(cursor.read(&mut [0u8; 4]).unwrap(), 0);
+        assert_eq!(cursor.write(b"i").unwrap(), 0);
+        assert_eq!(cursor.position(), 100);
+
+        let mut cursor = MapCursor::reader(&map);
+        cursor.seek(SeekFrom::Start(2)).unwrap();
+        let mut buf = String::new();
+        cursor.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "cdefgh");
+    }
+}
@@ -0,0 +1,685 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Loading of ELF executables
+//!
+//! An `Image` is an ELF64 executable (`ET_EXEC`) or shared object (`ET_DYN`)
+//! mapped into memory the way the kernel would map it: the whole span of
+//! the `PT_LOAD` segments is reserved first, then each segment is mapped
+//! from the file into the reservation with its own permissions and the
+//! memory past the end of its file data (the "bss") is zeroed. A page which
+//! is shared by several segments is copied from the file instead, and gets
+//! the permissions of all of them.
+//!
+//! ```rust
+//! use mmarinus::elf::Image;
+//!
+//! let image = Image::open("/proc/self/exe").unwrap();
+//! assert!(image.segments().iter().any(|s| s.perms() & libc::PROT_EXEC != 0));
+//! ```
+//!
+//! Only the image itself is loaded: no interpreter, relocations or
+//! dependencies are processed.
+
+use super::page::PageSize;
+use super::{perms, Error, Flags, Map};
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::mem::ManuallyDrop;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+
+const ELFCLASS64: u8 = 2;
+const EV_CURRENT: u8 = 1;
+
+#[cfg(target_endian = "little")]
+const ELFDATA: u8 = 1;
+
+#[cfg(target_endian = "big")]
+const ELFDATA: u8 = 2;
+
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+
+const PT_LOAD: u32 = 1;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+/// A `PT_LOAD` program header
+#[derive(Copy, Clone, Debug)]
+struct Header {
+    flags: u32,
+    offset: usize,
+    vaddr: usize,
+    filesz: usize,
+    memsz: usize,
+}
+
+/// A loaded segment of an `Image`
+#[derive(Debug)]
+pub struct Segment {
+    addr: usize,
+    size: usize,
+    prot: libc::c_int,
+    map: Map<perms::Unknown>,
+    bss: Option<Map<perms::Unknown>>,
+}
+
+impl Segment {
+    /// Gets the address of the segment (including the load bias)
+    ///
+    /// Like the virtual address in the program header, this need not be
+    /// aligned to the page size.
+    #[inline]
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    /// Gets the size of the segment in memory
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Gets the permissions (`PROT_*`) of the segment
+    #[inline]
+    pub fn perms(&self) -> libc::c_int {
+        self.prot
+    }
+
+    /// Gets the mapping of the pages backed by the file
+    ///
+    /// This is empty if the segment has no file data. Pages shared with
+    /// other segments are not part of it (nor of `Segment::bss()`).
+    #[inline]
+    pub fn map(&self) -> &Map<perms::Unknown> {
+        &self.map
+    }
+
+    /// Gets the mapping of the anonymous pages past the file data, if any
+    #[inline]
+    pub fn bss(&self) -> Option<&Map<perms::Unknown>> {
+        self.bss.as_ref()
+    }
+}
+
+/// An ELF executable mapped into memory
+///
+/// Dropping the image unmaps all of its segments.
+#[derive(Debug)]
+pub struct Image {
+    addr: usize,
+    size: usize,
+    bias: usize,
+    entry: usize,
+    segments: Vec<Segment>,
+
+    // Keeps the gaps between the segments reserved until the image is dropped.
+    _holes: Vec<Map<perms::None>>,
+
+    // The pages shared by segments.
+    _shared: Vec<Map<perms::Unknown>>,
+}
+
+impl Image {
+    /// Loads the ELF executable at `path`
+    ///
+    /// This is simply a convenience function for `Image::load()`.
+    #[inline]
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error<()>> {
+        Self::load(&mut File::open(path)?)
+    }
+
+    /// Loads an ELF executable from a file
+    ///
+    /// An `ET_EXEC` file is loaded at the addresses in its program headers;
+    /// if any of them are already mapped, this fails (see `Builder::at()`).
+    /// An `ET_DYN` file is loaded anywhere and the difference between the
+    /// actual and the linked addresses is the load bias (see `Image::bias()`).
+    ///
+    /// Malformed files fail with `ENOEXEC`. This includes
+    /// segments whose file offset and virtual address are not congruent
+    /// modulo the page size and segments which overlap.
+    pub fn load<U: AsRawFd>(file: &mut U) -> Result<Self, Error<()>> {
+        let (kind, entry, headers) = parse(file)?;
+        let shared = shared(&headers);
+
+        let page = PageSize::base();
+        let lo = headers[0].vaddr & !(page.bytes() - 1);
+        let hi = headers
+            .iter()
+            .map(|h| page.round_up(h.vaddr + h.memsz))
+            .try_fold(lo, |hi, end| end.map(|end| end.max(hi)))
+            .ok_or_else(invalid)?;
+
+        let builder = Map::bytes(hi - lo);
+        let builder = match kind {
+            ET_EXEC => builder.at(lo),
+            _ => builder.anywhere(),
+        };
+
+        let mut rest = builder
+            .anonymously()
+            .with_flags(Flags::NORESERVE)
+            .with(perms::None)?;
+
+        let addr = rest.addr();
+        let size = rest.size();
+        let bias = addr.wrapping_sub(lo);
+
+        let mut segments = Vec::with_capacity(headers.len());
+        let mut holes = Vec::new();
+        let mut pages = Vec::new();
+
+        for h in &headers {
+            let delta = h.vaddr & (page.bytes() - 1);
+            let first = h.vaddr - delta;
+            let last = (h.vaddr + h.memsz - 1) & !(page.bytes() - 1);
+
+            // The shared pages belong to none of the segments.
+            let start = match shared.contains(&first) {
+                false => first,
+                true => first + page.bytes(),
+            } + bias;
+
+            let end = match shared.contains(&last) {
+                false => last + page.bytes(),
+                true => last,
+            } + bias;
+
+            let end = end.max(start);
+            let data = match h.filesz {
+                0 => start,
+                n => page.round_up(h.vaddr + n).ok_or_else(invalid)? + bias,
+            };
+            let data = data.max(start).min(end);
+
+            // The first segment on a shared page maps it.
+            if shared.contains(&first) && start > rest.addr() {
+                let (map, next) = rest.split_at(start).map_err(|e| e.err)?;
+                pages.push(fill(map, first, &headers, file)?);
+                rest = next;
+            }
+
+            // Keep the gaps between the segments reserved.
+            if start > rest.addr() {
+                let (hole, next) = rest.split_at(start).map_err(|e| e.err)?;
+                holes.push(hole);
+                rest = next;
+            }
+
+            let (segment, next) = rest.split_at(end).map_err(|e| e.err)?;
+            let (filed, anon) = segment.split_at(data).map_err(|e| e.err)?;
+            rest = next;
+
+            let prot = protection(h.flags);
+
+            let skipped = start - (first + bias);
+            let offset = (h.offset - delta + skipped)
+                .try_into()
+                .map_err(|_| invalid())?;
+
+            let tail = (delta + h.filesz).saturating_sub(skipped);
+            let map = match filed.size() {
+                0 => filed.reprotect(perms::Unknown(prot)).map_err(|e| e.err)?,
+
+                // The rest of the last page of file data belongs to the bss.
+                n if h.memsz > h.filesz && tail < n => {
+                    let mut map = filed
+                        .remap()
+                        .from(file, offset)
+                        .with(perms::ReadWrite)
+                        .map_err(|e| e.err)?;
+
+                    for byte in &mut map[tail..] {
+                        *byte = 0;
+                    }
+
+                    map.reprotect(perms::Unknown(prot)).map_err(|e| e.err)?
+                }
+
+                _ => filed
+                    .remap()
+                    .from(file, offset)
+                    .with(perms::Unknown(prot))
+                    .map_err(|e| e.err)?,
+            };
+
+            let bss = match anon.size() {
+                0 => None,
+                _ => Some(
+                    anon.remap()
+                        .anonymously()
+                        .with(perms::Unknown(prot))
+                        .map_err(|e| e.err)?,
+                ),
+            };
+
+            segments.push(Segment {
+                addr: h.vaddr + bias,
+                size: h.memsz,
+                prot,
+                map,
+                bss,
+            });
+        }
+
+        Ok(Self {
+            addr,
+            size,
+            bias,
+            entry: entry.wrapping_add(bias),
+            segments,
+            _holes: holes,
+            _shared: pages,
+        })
+    }
+
+    /// Gets the lowest address of the image
+    #[inline]
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    /// Gets the size of the address range spanned by the image
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Gets the load bias
+    ///
+    /// This is the difference between the addresses at which the image was
+    /// loaded and the addresses in its program headers. It is always zero
+    /// for `ET_EXEC` files.
+    #[inline]
+    pub fn bias(&self) -> usize {
+        self.bias
+    }
+
+    /// Gets the address of the entry point (including the load bias)
+    #[inline]
+    pub fn entry(&self) -> usize {
+        self.entry
+    }
+
+    /// Gets the loaded segments in the order of their addresses
+    #[inline]
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+}
+
+/// The error of malformed files
+fn invalid() -> std::io::Error {
+    std::io::Error::from_raw_os_error(libc::ENOEXEC)
+}
+
+/// Reads and validates the file header and the `PT_LOAD` program headers
+fn parse<U: AsRawFd>(file: &mut U) -> std::io::Result<(u16, usize, Vec<Header>)> {
+    let size = |value: u64| -> std::io::Result<usize> { value.try_into().map_err(|_| invalid()) };
+
+    // Borrow the file descriptor without closing it.
+    let file = ManuallyDrop::new(unsafe { File::from_raw_fd(file.as_raw_fd()) });
+    let len = file.metadata()?.len();
+
+    let mut ehdr = [0u8; EHDR_SIZE];
+    file.read_exact_at(&mut ehdr, 0)?;
+
+    if ehdr[..4] != *b"\x7fELF" || ehdr[4] != ELFCLASS64 || ehdr[5] != ELFDATA {
+        return Err(invalid());
+    }
+
+    let kind = u16(&ehdr, 16);
+    if ehdr[6] != EV_CURRENT || (kind != ET_EXEC && kind != ET_DYN) {
+        return Err(invalid());
+    }
+
+    let entry = size(u64(&ehdr, 24))?;
+    let phoff = u64(&ehdr, 32);
+    let phentsize = usize::from(u16(&ehdr, 54));
+    let phnum = usize::from(u16(&ehdr, 56));
+    if phentsize != PHDR_SIZE {
+        return Err(invalid());
+    }
+
+    let end = phoff.checked_add((phentsize * phnum) as u64);
+    if end.map_or(true, |end| end > len) {
+        return Err(invalid());
+    }
+
+    let mut phdrs = vec![0u8; phentsize * phnum];
+    file.read_exact_at(&mut phdrs, phoff)?;
+
+    let page = PageSize::base().bytes();
+    let mut headers: Vec<Header> = Vec::new();
+    for phdr in phdrs.chunks(phentsize) {
+        if u32(phdr, 0) != PT_LOAD || u64(phdr, 40) == 0 {
+            continue;
+        }
+
+        let h = Header {
+            flags: u32(phdr, 4),
+            offset: size(u64(phdr, 8))?,
+            vaddr: size(u64(phdr, 16))?,
+            filesz: size(u64(phdr, 32))?,
+            memsz: size(u64(phdr, 40))?,
+        };
+
+        let data = (h.offset as u64).checked_add(h.filesz as u64);
+        let end = h
+            .vaddr
+            .checked_add(h.memsz)
+            .and_then(|e| e.checked_add(page));
+        if data.map_or(true, |d| d > len) || end.is_none() {
+            return Err(invalid());
+        }
+
+        if h.filesz > h.memsz || h.offset % page != h.vaddr % page {
+            return Err(invalid());
+        }
+
+        // Segments must be sorted and must not overlap.
+        if let Some(prev) = headers.last() {
+            if prev.vaddr + prev.memsz > h.vaddr {
+                return Err(invalid());
+            }
+        }
+
+        headers.push(h);
+    }
+
+    if headers.is_empty() {
+        return Err(invalid());
+    }
+
+    Ok((kind, entry, headers))
+}
+
+/// Finds the pages (by virtual address) which are shared by segments
+fn shared(headers: &[Header]) -> Vec<usize> {
+    let mask = !(PageSize::base().bytes() - 1);
+    let mut pages: Vec<usize> = headers
+        .windows(2)
+        .map(|w| ((w[0].vaddr + w[0].memsz - 1) & mask, w[1].vaddr & mask))
+        .filter(|(last, first)| last == first)
+        .map(|(page, _)| page)
+        .collect();
+
+    pages.dedup();
+    pages
+}
+
+/// Fills a page shared by segments from the file
+///
+/// The page is mapped anonymously, so the bytes which are not file data of
+/// any of the segments are zero. It gets the permissions of all of them.
+fn fill<U: AsRawFd>(
+    map: Map<perms::None>,
+    vaddr: usize,
+    headers: &[Header],
+    file: &mut U,
+) -> Result<Map<perms::Unknown>, Error<()>> {
+    let file = ManuallyDrop::new(unsafe { File::from_raw_fd(file.as_raw_fd()) });
+    let end = vaddr + map.size();
+
+    let mut map = map
+        .remap()
+        .anonymously()
+        .with(perms::ReadWrite)
+        .map_err(|e| e.err)?;
+
+    let mut prot = libc::PROT_NONE;
+    for h in headers {
+        if h.vaddr >= end || h.vaddr + h.memsz <= vaddr {
+            continue;
+        }
+
+        prot |= protection(h.flags);
+        let lo = h.vaddr.max(vaddr);
+        let hi = (h.vaddr + h.filesz).min(end);
+        if lo < hi {
+            let offset = (h.offset + (lo - h.vaddr)) as u64;
+            file.read_exact_at(&mut map[lo - vaddr..hi - vaddr], offset)?;
+        }
+    }
+
+    map.reprotect(perms::Unknown(prot))
+        .map_err(|e| e.err.into())
+}
+
+/// Converts the flags of a program header to permissions (`PROT_*`)
+fn protection(flags: u32) -> libc::c_int {
+    [
+        (PF_R, libc::PROT_READ),
+        (PF_W, libc::PROT_WRITE),
+        (PF_X, libc::PROT_EXEC),
+    ]
+    .iter()
+    .filter(|(flag, _)| flags & flag != 0)
+    .fold(libc::PROT_NONE, |prot, (_, p)| prot | p)
+}
+
+fn u16(buf: &[u8], offset: usize) -> u16 {
+    let mut bytes = [0u8; 2];
+    bytes.copy_from_slice(&buf[offset..offset + 2]);
+    u16::from_ne_bytes(bytes)
+}
+
+fn u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_ne_bytes(bytes)
+}
+
+fn u64(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_ne_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::slice::from_raw_parts;
+
+    /// Builds an ELF file with the specified `PT_LOAD` headers
+    fn build(kind: u16, entry: u64, loads: &[(u32, u64, u64, u64, u64)], len: usize) -> Vec<u8> {
+        let mut elf = vec![0xbbu8; len];
+        elf[..EHDR_SIZE].copy_from_slice(&[0; EHDR_SIZE]);
+        elf[..4].copy_from_slice(b"\x7fELF");
+        elf[4] = ELFCLASS64;
+        elf[5] = ELFDATA;
+        elf[6] = EV_CURRENT;
+        elf[16..18].copy_from_slice(&kind.to_ne_bytes());
+        elf[24..32].copy_from_slice(&entry.to_ne_bytes());
+        elf[32..40].copy_from_slice(&(EHDR_SIZE as u64).to_ne_bytes());
+        elf[54..56].copy_from_slice(&(PHDR_SIZE as u16).to_ne_bytes());
+        elf[56..58].copy_from_slice(&(loads.len() as u16).to_ne_bytes());
+
+        for (i, (flags, offset, vaddr, filesz, memsz)) in loads.iter().enumerate() {
+            let phdr = &mut elf[EHDR_SIZE + i * PHDR_SIZE..][..PHDR_SIZE];
+            phdr[0..4].copy_from_slice(&PT_LOAD.to_ne_bytes());
+            phdr[4..8].copy_from_slice(&flags.to_ne_bytes());
+            phdr[8..16].copy_from_slice(&offset.to_ne_bytes());
+            phdr[16..24].copy_from_slice(&vaddr.to_ne_bytes());
+            phdr[32..40].copy_from_slice(&filesz.to_ne_bytes());
+            phdr[40..48].copy_from_slice(&memsz.to_ne_bytes());
+        }
+
+        elf
+    }
+
+    fn write(name: &str, elf: &[u8]) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("mmarinus-elf-{}-{}", std::process::id(), name));
+        File::create(&path).unwrap().write_all(elf).unwrap();
+        path
+    }
+
+    #[test]
+    fn dyn_bss() {
+        let page = PageSize::base().bytes() as u64;
+        let mut elf = build(
+            ET_DYN,
+            0x10,
+            &[
+                (PF_R | PF_X, 0, 0, 0x200, 0x200),
+                (PF_R | PF_W, 0x300, page * 2 + 0x300, 0x40, page * 2),
+            ],
+            page as usize,
+        );
+        elf[0x300..0x340].copy_from_slice(&[0xaa; 0x40]);
+
+        let path = write("dyn", &elf);
+        let image = Image::open(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(image.size(), page as usize * 5);
+        assert_eq!(image.bias(), image.addr());
+        assert_eq!(image.entry(), image.addr() + 0x10);
+
+        let segments = image.segments();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].perms(), libc::PROT_READ | libc::PROT_EXEC);
+        assert!(segments[0].bss().is_none());
+
+        let data = &segments[1];
+        assert_eq!(data.addr(), image.addr() + page as usize * 2 + 0x300);
+        assert_eq!(data.map().size(), page as usize);
+        assert_eq!(data.bss().unwrap().size(), page as usize * 2);
+
+        let bytes = unsafe { from_raw_parts(data.addr() as *const u8, data.size()) };
+        assert!(bytes[..0x40].iter().all(|b| *b == 0xaa));
+        assert!(bytes[0x40..].iter().all(|b| *b == 0));
+
+        // The page between the segments stays reserved.
+        assert_eq!(image._holes.len(), 1);
+        assert_eq!(image._holes[0].addr(), image.addr() + page as usize);
+    }
+
+    #[test]
+    fn shared() {
+        let page = PageSize::base().bytes();
+        let mut elf = build(
+            ET_DYN,
+            0,
+            &[
+                (PF_R | PF_X, 0, 0, 0x200, 0x200),
+                (PF_R | PF_W, 0x280, 0x280, 0x40, page as u64),
+            ],
+            0x400,
+        );
+        elf[0x280..0x2c0].copy_from_slice(&[0xaa; 0x40]);
+
+        let path = write("shared", &elf);
+        let image = Image::open(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let segments = image.segments();
+        assert_eq!(image.size(), page * 2);
+        assert_eq!(segments[0].map().size(), 0);
+        assert_eq!(segments[1].map().size(), 0);
+        assert_eq!(segments[1].bss().unwrap().addr(), image.addr() + page);
+
+        // The shared page has the permissions of both segments.
+        assert_eq!(image._shared.len(), 1);
+        assert_eq!(image._shared[0].addr(), image.addr());
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+        let line = format!("{:x}-", image.addr());
+        let line = maps.lines().find(|l| l.starts_with(&line)).unwrap();
+        assert_eq!(line.split(' ').nth(1), Some("rwxp"));
+
+        let text = unsafe { from_raw_parts(segments[0].addr() as *const u8, 0x200) };
+        assert!(text == &elf[..0x200]);
+
+        let data = unsafe { from_raw_parts(segments[1].addr() as *const u8, page) };
+        assert!(data[..0x40].iter().all(|b| *b == 0xaa));
+        assert!(data[0x40..].iter().all(|b| *b == 0));
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn exec() {
+        const BASE: u64 = 0x10_0000_0000;
+
+        let page = PageSize::base().bytes() as u64;
+        let elf = build(ET_EXEC, BASE, &[(PF_R, 0, BASE, 0x100, 0x100)], 0x100);
+
+        let path = write("exec", &elf);
+        let image = Image::open(&path);
+        std::fs::remove_file(path).unwrap();
+
+        let image = image.unwrap();
+        assert_eq!(image.addr(), BASE as usize);
+        assert_eq!(image.size(), page as usize);
+        assert_eq!(image.bias(), 0);
+        assert_eq!(image.entry(), BASE as usize);
+    }
+
+    #[test]
+    fn invalid() {
+        let mut cases = vec![
+            build(1, 0, &[(PF_R, 0, 0, 0x100, 0x100)], 0x200),
+            build(ET_DYN, 0, &[], 0x200),
+            build(ET_DYN, 0, &[(PF_R, 0, 0, 0x400, 0x400)], 0x200),
+            build(ET_DYN, 0, &[(PF_R, 0, 0x10, 0x100, 0x100)], 0x200),
+            build(ET_DYN, 0, &[(PF_R, 0, 0, 0x100, 0x80)], 0x200),
+            build(
+                ET_DYN,
+                0,
+                &[(PF_R, 0, 0, 0x100, 0x100), (PF_R, 0x80, 0x80, 0x100, 0x100)],
+                0x200,
+            ),
+        ];
+
+        // The program headers must have the expected size.
+        let mut elf = build(ET_DYN, 0, &[(PF_R, 0, 0, 0x100, 0x100)], 0x200);
+        elf[54..56].copy_from_slice(&(PHDR_SIZE as u16 + 8).to_ne_bytes());
+        cases.push(elf);
+
+        // The program headers must be within the file.
+        let mut elf = build(ET_DYN, 0, &[(PF_R, 0, 0, 0x100, 0x100)], 0x200);
+        elf[56..58].copy_from_slice(&u16::MAX.to_ne_bytes());
+        cases.push(elf);
+
+        for (i, elf) in cases.iter().enumerate() {
+            let path = write(&format!("invalid{}", i), elf);
+            let err = Image::open(&path).unwrap_err();
+            std::fs::remove_file(path).unwrap();
+            assert_eq!(err.err.raw_os_error(), Some(libc::ENOEXEC), "case {}", i);
+        }
+    }
+
+    #[test]
+    fn exe() {
+        let mut exe = File::open("/proc/self/exe").unwrap();
+        let image = match Image::load(&mut exe) {
+            // A static executable cannot be loaded over itself.
+            Err(e) => return assert_eq!(e.err.raw_os_error(), Some(libc::EEXIST)),
+            Ok(image) => image,
+        };
+
+        let (_, _, headers) = parse(&mut exe).unwrap();
+        assert_eq!(image.segments().len(), headers.len());
+
+        for (segment, h) in image.segments().iter().zip(headers) {
+            assert_eq!(segment.addr(), h.vaddr + image.bias());
+
+            if segment.perms() & libc::PROT_READ != 0 {
+                let mut expected = vec![0u8; h.filesz];
+                exe.read_exact_at(&mut expected, h.offset as u64).unwrap();
+
+                let bytes = unsafe { from_raw_parts(segment.addr() as *const u8, h.filesz) };
+                assert!(bytes == &expected[..]);
+            }
+        }
+    }
+}
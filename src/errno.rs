@@ -0,0 +1,173 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::error::Operation;
+
+use core::hash::{Hash, Hasher};
+
+/// An error number (`errno`)
+///
+/// This is the error type of mappings (see `Error::err`), regardless of the
+/// `std` feature. With it, the error converts to and from `std::io::Error`
+/// and `kind()` classifies it like `std::io::Error::kind()`.
+///
+/// Errors of this crate also record the operation which failed, if known
+/// (see `Error::op()`). Errors compare equal if their numbers are equal.
+///
+/// ```rust
+/// use mmarinus::Errno;
+///
+/// let err = Errno::from_raw_os_error(libc::EINVAL);
+/// assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+/// ```
+#[derive(Copy, Clone, Debug)]
+pub struct Errno(libc::c_int, Option<Operation>);
+
+impl PartialEq for Errno {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for Errno {}
+
+impl Hash for Errno {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+impl Errno {
+    /// Gets the error of the last failed system call on this thread
+    #[inline]
+    pub fn last_os_error() -> Self {
+        #[cfg(target_os = "linux")]
+        let errno = unsafe { *libc::__errno_location() };
+
+        #[cfg(target_os = "macos")]
+        let errno = unsafe { *libc::__error() };
+
+        Self(errno, None)
+    }
+
+    /// Creates an error from an error number
+    #[inline]
+    pub const fn from_raw_os_error(errno: libc::c_int) -> Self {
+        Self(errno, None)
+    }
+
+    /// Gets the error number
+    ///
+    /// This always returns `Some`. The `Option` mirrors `std::io::Error`.
+    #[inline]
+    pub const fn raw_os_error(&self) -> Option<libc::c_int> {
+        Some(self.0)
+    }
+
+    /// Records the operation which failed
+    #[inline]
+    pub(crate) fn with(self, op: Operation) -> Self {
+        Self(self.0, Some(op))
+    }
+
+    /// Gets the operation which failed, if known
+    #[inline]
+    pub(crate) fn op(&self) -> Option<&Operation> {
+        self.1.as_ref()
+    }
+}
+
+impl core::fmt::Display for Errno {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "os error {}", self.0)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Errno {
+    /// Gets the kind of the error, as for `std::io::Error`
+    #[inline]
+    pub fn kind(&self) -> std::io::ErrorKind {
+        std::io::Error::from_raw_os_error(self.0).kind()
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Errno {}
+
+/// Errors without an error number get the number of their kind, if any.
+#[cfg(feature = "std")]
+impl From<std::io::Error> for Errno {
+    fn from(value: std::io::Error) -> Self {
+        use std::io::ErrorKind::*;
+
+        let errno = match value.raw_os_error() {
+            Some(errno) => errno,
+            None => match value.kind() {
+                NotFound => libc::ENOENT,
+                PermissionDenied => libc::EACCES,
+                AlreadyExists => libc::EEXIST,
+                InvalidInput | InvalidData => libc::EINVAL,
+                OutOfMemory => libc::ENOMEM,
+                Unsupported => libc::ENOSYS,
+                _ => libc::EIO,
+            },
+        };
+
+        Self(errno, None)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<Errno> for std::io::Error {
+    #[inline]
+    fn from(value: Errno) -> Self {
+        Self::from_raw_os_error(value.0)
+    }
+}
+
+/// The error type of the crate
+pub(crate) type OsError = Errno;
+
+/// The result type of the crate
+pub(crate) type Result<T> = core::result::Result<T, OsError>;
+
+/// Gets the error of the last failed system call on this thread
+#[inline]
+pub(crate) fn last() -> OsError {
+    OsError::last_os_error()
+}
+
+/// Creates an error from an error number
+#[inline]
+pub(crate) fn from_raw(errno: libc::c_int) -> OsError {
+    OsError::from_raw_os_error(errno)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last() {
+        assert_ne!(unsafe { libc::close(-1) }, 0);
+        assert_eq!(
+            Errno::last_os_error(),
+            Errno::from_raw_os_error(libc::EBADF)
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn io() {
+        use std::io::{Error, ErrorKind};
+
+        let err: Error = Errno::from_raw_os_error(libc::ENOENT).into();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(Errno::from(err).kind(), ErrorKind::NotFound);
+
+        let err = Errno::from(Error::from(ErrorKind::InvalidInput));
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    }
+}
@@ -1,53 +1,337 @@
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::errno::{Errno, OsError};
+use crate::page::PageSize;
+
+#[cfg(feature = "std")]
+use std::io::ErrorKind;
+
 /// The error condition
 ///
-/// This type is mostly a wrapper for `std::io::Error` with one additional
+/// This type is mostly a wrapper for `Errno` with one additional
 /// feature: it conveys ownership to a mapping. This enables the pattern
 /// where an old mapping is valid until the conversion operation is successful.
 /// If the operation is unsuccessful, the old mapping is returned along with
 /// the error condition.
+///
+/// Where possible, the error also records the operation which failed (see
+/// `Error::op()`), which is used to classify the error (see `Error::reason()`).
 #[derive(Debug)]
 pub struct Error<M> {
     /// The previous mapping that could not be modified
     pub map: M,
 
     /// The underlying error
-    pub err: std::io::Error,
+    pub err: Errno,
+}
+
+/// A memory management operation
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Op {
+    /// Creating a mapping (`mmap()`)
+    Map,
+
+    /// Changing the permissions of a mapping (`mprotect()`)
+    Protect,
+
+    /// Removing a mapping (`munmap()`)
+    Unmap,
+
+    /// Moving or resizing a mapping (`mremap()`)
+    Remap,
+
+    /// Splitting a mapping in two
+    Split,
+
+    /// Sealing a mapping (`mseal()`)
+    Seal,
+
+    /// Setting the NUMA memory policy of a mapping (`mbind()`)
+    Bind,
+
+    /// Locking the pages of a mapping into memory (`mlock()`)
+    Lock,
+}
+
+/// The operation which caused an error, along with its arguments
+///
+/// For `Op::Map`, these are the arguments of the last attempt; for example,
+/// the flags may include `MAP_HUGETLB`. For `Op::Split`, `addr` is the
+/// requested split address.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Operation {
+    /// The operation
+    pub op: Op,
+
+    /// The (requested) address
+    pub addr: usize,
+
+    /// The size
+    pub size: usize,
+
+    /// The permissions (`PROT_*`)
+    pub prot: libc::c_int,
+
+    /// The flags (`MAP_*` or `MREMAP_*`)
+    pub flags: libc::c_int,
+}
+
+/// The likely reason for an error
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Reason {
+    /// The requested address range is already mapped
+    AddressInUse,
+
+    /// Not enough huge pages are reserved or supported
+    HugePagesUnavailable,
+
+    /// Executable file mappings are forbidden by the mount (`noexec`)
+    NoExecMount,
+
+    /// The limit of locked memory is exceeded (`RLIMIT_MEMLOCK`)
+    MemlockLimit,
+
+    /// An address, offset or size is not aligned to the page size
+    Misaligned,
+
+    /// There is not enough free address space or memory
+    OutOfAddressSpace,
+
+    /// The operation is not supported by the system
+    Unsupported,
+
+    /// The budget for live mappings is exceeded (see the `tracking` module)
+    BudgetExceeded,
+
+    /// Any other reason
+    Other,
+}
+
+impl<M> Error<M> {
+    /// Creates a new error without operation context
+    #[inline]
+    pub fn new(map: M, err: OsError) -> Self {
+        Self { map, err }
+    }
+
+    /// Gets the operation which failed, if known
+    #[inline]
+    pub fn op(&self) -> Option<&Operation> {
+        self.err.op()
+    }
+
+    /// Classifies the error using the operation which failed
+    pub fn reason(&self) -> Reason {
+        let op = match self.err.op() {
+            Some(op) => *op,
+            None => return Reason::Other,
+        };
+
+        let huge = op.op == Op::Map && op.flags & HUGETLB != 0;
+
+        match (op.op, self.err.raw_os_error()) {
+            (Op::Map, Some(libc::EDQUOT)) => Reason::BudgetExceeded,
+            (_, Some(libc::EINVAL)) if op.exact() && op.addr & (op.align() - 1) != 0 => {
+                Reason::Misaligned
+            }
+            (_, Some(libc::ENOMEM | libc::EINVAL)) if huge => Reason::HugePagesUnavailable,
+            (Op::Map, Some(libc::EEXIST)) => Reason::AddressInUse,
+            (Op::Map | Op::Protect, Some(libc::EPERM)) if op.prot & libc::PROT_EXEC != 0 => {
+                Reason::NoExecMount
+            }
+            (Op::Map, Some(libc::EAGAIN)) if op.flags & LOCKED != 0 => Reason::MemlockLimit,
+            (Op::Lock, Some(libc::ENOMEM | libc::EAGAIN | libc::EPERM)) => Reason::MemlockLimit,
+            (Op::Map | Op::Remap, Some(libc::ENOMEM)) => Reason::OutOfAddressSpace,
+            (_, Some(libc::ENOSYS)) => Reason::Unsupported,
+            _ => Reason::Other,
+        }
+    }
+
+    /// Converts the owned value of the error
+    #[inline]
+    pub fn map_inner<N, F: FnOnce(M) -> N>(self, f: F) -> Error<N> {
+        Error {
+            map: f(self.map),
+            err: self.err,
+        }
+    }
+}
+
+impl Operation {
+    /// Indicates whether the address is used as it is, not only as a hint
+    fn exact(&self) -> bool {
+        self.op != Op::Map || self.flags & FIXED != 0
+    }
+
+    /// Gets the alignment of the pages of the operation
+    ///
+    /// This is the huge page size for mappings of huge pages.
+    fn align(&self) -> usize {
+        let base = PageSize::base().bytes();
+
+        #[cfg(target_os = "linux")]
+        if self.op == Op::Map && self.flags & HUGETLB != 0 {
+            let shift = (self.flags >> libc::MAP_HUGE_SHIFT) & libc::MAP_HUGE_MASK;
+            return match shift as u32 {
+                0 => PageSize::default_huge().map_or(base, |p| p.bytes()),
+                shift if shift < usize::BITS => 1 << shift,
+                _ => base,
+            };
+        }
+
+        base
+    }
 }
 
-impl<M> std::fmt::Display for Error<M> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+#[cfg(target_os = "linux")]
+const HUGETLB: libc::c_int = libc::MAP_HUGETLB;
+
+#[cfg(not(target_os = "linux"))]
+const HUGETLB: libc::c_int = 0;
+
+#[cfg(target_os = "linux")]
+const FIXED: libc::c_int = libc::MAP_FIXED | libc::MAP_FIXED_NOREPLACE;
+
+#[cfg(not(target_os = "linux"))]
+const FIXED: libc::c_int = libc::MAP_FIXED;
+
+#[cfg(target_os = "linux")]
+const LOCKED: libc::c_int = libc::MAP_LOCKED;
+
+#[cfg(not(target_os = "linux"))]
+const LOCKED: libc::c_int = 0;
+
+impl<M> core::fmt::Display for Error<M> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
         self.err.fmt(f)
     }
 }
 
+#[cfg(feature = "std")]
 impl<M: std::fmt::Debug> std::error::Error for Error<M> {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         Some(&self.err)
     }
 }
 
-impl<M> From<Error<M>> for std::io::Error {
+impl<M> From<Error<M>> for OsError {
     fn from(value: Error<M>) -> Self {
         value.err
     }
 }
 
+#[cfg(feature = "std")]
+impl<M> From<Error<M>> for std::io::Error {
+    fn from(value: Error<M>) -> Self {
+        value.err.into()
+    }
+}
+
+#[cfg(feature = "std")]
 impl From<std::io::Error> for Error<()> {
     fn from(value: std::io::Error) -> Self {
-        Self {
-            map: (),
-            err: value,
-        }
+        Self::new((), value.into())
     }
 }
 
-impl From<std::io::ErrorKind> for Error<()> {
-    fn from(value: std::io::ErrorKind) -> Self {
-        Self {
+impl From<OsError> for Error<()> {
+    fn from(value: OsError) -> Self {
+        Self::new((), value)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<ErrorKind> for Error<()> {
+    fn from(value: ErrorKind) -> Self {
+        Self::new((), std::io::Error::from(value).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::page::PageSize;
+    use crate::{perms, Map};
+
+    use super::*;
+
+    #[test]
+    fn in_use() {
+        let map = Map::bytes(PageSize::base().bytes())
+            .anywhere()
+            .anonymously()
+            .with(perms::Read)
+            .unwrap();
+
+        let err = Map::bytes(map.size())
+            .at(map.addr())
+            .anonymously()
+            .with(perms::Read)
+            .unwrap_err();
+
+        let op = *err.op().unwrap();
+        assert_eq!(op.op, Op::Map);
+        assert_eq!(op.addr, map.addr());
+        assert_eq!(op.size, map.size());
+        assert_eq!(op.prot, libc::PROT_READ);
+        assert_ne!(op.flags & libc::MAP_FIXED_NOREPLACE, 0);
+        assert_eq!(err.reason(), Reason::AddressInUse);
+    }
+
+    #[test]
+    fn misaligned() {
+        let map = Map::bytes(PageSize::base().bytes() * 2)
+            .anywhere()
+            .anonymously()
+            .with(perms::Read)
+            .unwrap();
+
+        let err = map.split(1).unwrap_err();
+        assert_eq!(err.op().unwrap().op, Op::Split);
+        assert_eq!(err.reason(), Reason::Misaligned);
+
+        let err = err.map_inner(|map| map.size());
+        assert_eq!(err.map, PageSize::base().bytes() * 2);
+        assert_eq!(err.reason(), Reason::Misaligned);
+
+        let err = Error::new((), crate::errno::from_raw(libc::EBADF));
+        assert_eq!(err.reason(), Reason::Other);
+        assert!(err.op().is_none());
+    }
+
+    #[test]
+    fn huge() {
+        // No system has 1 TiB huge pages.
+        let err = Map::bytes(PageSize::base().bytes())
+            .anywhere()
+            .anonymously()
+            .with_huge_pages(40)
+            .with(perms::Read)
+            .unwrap_err();
+
+        assert_ne!(err.op().unwrap().flags & libc::MAP_HUGETLB, 0);
+        assert_eq!(err.reason(), Reason::HugePagesUnavailable);
+
+        // Addresses which are not aligned to the huge page size come first.
+        let psize = PageSize::base().bytes();
+        let flags = libc::MAP_PRIVATE | libc::MAP_HUGETLB | 21 << libc::MAP_HUGE_SHIFT;
+        let err = |addr, flags| Error {
             map: (),
-            err: value.into(),
-        }
+            err: crate::errno::from_raw(libc::EINVAL).with(Operation {
+                op: Op::Map,
+                addr,
+                size: 1 << 21,
+                prot: libc::PROT_READ,
+                flags,
+            }),
+        };
+
+        let fixed = flags | libc::MAP_FIXED_NOREPLACE;
+        assert_eq!(err(psize, fixed).reason(), Reason::Misaligned);
+        assert_eq!(err(1 << 21, fixed).reason(), Reason::HugePagesUnavailable);
+
+        // The kernel ignores the alignment of hints.
+        assert_eq!(err(psize, flags).reason(), Reason::HugePagesUnavailable);
     }
 }
@@ -0,0 +1,128 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use core::ops::{BitAnd, BitOr, BitOrAssign};
+
+// Not exported by libc for all targets; see `include/uapi/asm-generic/mman-common.h`.
+#[cfg(target_os = "linux")]
+const MAP_UNINITIALIZED: libc::c_int = 0x4000000;
+
+/// Additional flags for a mapping
+///
+/// These flags are passed to `mmap()` in addition to the flags derived from
+/// the other builder stages. Flags can be combined with `|`:
+///
+/// ```rust
+/// use mmarinus::{Flags, Map, perms};
+///
+/// let map = Map::bytes(4096)
+///     .anywhere()
+///     .anonymously()
+///     .with_flags(Flags::POPULATE | Flags::NORESERVE)
+///     .with(perms::ReadWrite)
+///     .unwrap();
+///
+/// assert_eq!(&*map, &[0; 4096]);
+/// ```
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Flags(libc::c_int);
+
+#[cfg(target_os = "linux")]
+impl Flags {
+    /// Prefaults the pages of the mapping (`MAP_POPULATE`)
+    pub const POPULATE: Self = Self(libc::MAP_POPULATE);
+
+    /// Does not reserve swap space for the mapping (`MAP_NORESERVE`)
+    pub const NORESERVE: Self = Self(libc::MAP_NORESERVE);
+
+    /// Marks the mapping as suitable for a stack (`MAP_STACK`)
+    pub const STACK: Self = Self(libc::MAP_STACK);
+
+    /// Lets the mapping grow downwards (`MAP_GROWSDOWN`)
+    ///
+    /// Only valid for anonymous mappings.
+    pub const GROWSDOWN: Self = Self(libc::MAP_GROWSDOWN);
+
+    /// Places the mapping in the first 2GiB of the address space (`MAP_32BIT`)
+    #[cfg(target_arch = "x86_64")]
+    pub const BIT32: Self = Self(libc::MAP_32BIT);
+
+    /// Does not clear the pages of the mapping (`MAP_UNINITIALIZED`)
+    ///
+    /// Only valid for anonymous mappings. The kernel ignores this flag unless
+    /// it was built with `CONFIG_MMAP_ALLOW_UNINITIALIZED`.
+    pub const UNINITIALIZED: Self = Self(MAP_UNINITIALIZED);
+
+    /// Guarantees synchronous page faults for DAX files (`MAP_SYNC`)
+    ///
+    /// Only valid for file-backed shared mappings. When this flag is used,
+    /// the mapping is automatically created with `MAP_SHARED_VALIDATE` so
+    /// that kernels which do not support it reject the mapping.
+    pub const SYNC: Self = Self(libc::MAP_SYNC);
+
+    /// Flags which are only valid for anonymous mappings
+    pub(crate) const ANONYMOUS_ONLY: Self = Self(libc::MAP_GROWSDOWN | MAP_UNINITIALIZED);
+}
+
+impl Flags {
+    /// Returns an empty set of flags
+    #[inline]
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Returns the raw value of the flags
+    #[inline]
+    pub const fn bits(self) -> libc::c_int {
+        self.0
+    }
+
+    /// Returns `true` if no flags are set
+    #[inline]
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns `true` if all of the flags in `other` are set
+    #[inline]
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns `true` if any of the flags in `other` are set
+    #[inline]
+    pub const fn intersects(self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    /// Returns the flags without the flags in `other`
+    #[cfg(all(target_os = "linux", feature = "std"))]
+    #[inline]
+    pub(crate) const fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+}
+
+impl BitOr for Flags {
+    type Output = Self;
+
+    #[inline]
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for Flags {
+    #[inline]
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0
+    }
+}
+
+impl BitAnd for Flags {
+    type Output = Self;
+
+    #[inline]
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
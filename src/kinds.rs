@@ -0,0 +1,78 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Kinds of a mapping
+//!
+//! The kind of a mapping determines whether its pages are shared with other
+//! mappings of the same object and, therefore, whether Rust references to
+//! the contents of the mapping can be handed out safely. Only `Private`
+//! mappings implement `Deref`; all other kinds must be accessed using the
+//! copying APIs on `Map` (i.e. `Map::copy_to()` and `Map::copy_from()`).
+
+use super::map::{KnownKind, Safe};
+
+/// Indicates a private mapping
+#[derive(Debug)]
+pub struct Private;
+
+impl Safe for Private {}
+
+impl KnownKind for Private {
+    const KIND: libc::c_int = libc::MAP_PRIVATE;
+}
+
+/// Indicates a shared mapping
+#[derive(Debug)]
+pub struct Shared;
+
+impl KnownKind for Shared {
+    const KIND: libc::c_int = libc::MAP_SHARED;
+}
+
+/// Indicates a shared mapping which validates its flags
+///
+/// This is identical to `Shared` except that the kernel rejects the mapping
+/// with `EOPNOTSUPP` if any of the flags are unknown, rather than silently
+/// ignoring them.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+pub struct SharedValidate;
+
+#[cfg(target_os = "linux")]
+impl KnownKind for SharedValidate {
+    const KIND: libc::c_int = libc::MAP_SHARED_VALIDATE;
+}
+
+/// Indicates a droppable mapping
+///
+/// Droppable mappings are private anonymous mappings whose pages the kernel
+/// may free (i.e. zero) at any time under memory pressure. This requires
+/// Linux 6.11 or later.
+///
+/// Because the contents of a droppable mapping can vanish at any point, no
+/// references to its contents are provided. Instead, data must be copied in
+/// and out using `Map::copy_from()` and `Map::copy_to()`. The data returned
+/// by `Map::copy_to()` may be wholly or partially zeroed and callers must be
+/// prepared to regenerate it.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+pub struct Droppable;
+
+#[cfg(target_os = "linux")]
+impl KnownKind for Droppable {
+    // Not exported by older versions of libc; see `include/uapi/linux/mman.h`.
+    const KIND: libc::c_int = 0x08;
+}
+
+/// A runtime mapping kind
+///
+/// The contained value is passed verbatim as the mapping type in the flags
+/// given to `mmap()`. A mapping with an `Unknown` kind is never considered
+/// to be safe to reference directly.
+#[derive(Debug)]
+pub struct Unknown(pub libc::c_int);
+
+impl super::map::Kind for Unknown {
+    #[inline]
+    fn kind(self) -> libc::c_int {
+        self.0
+    }
+}
@@ -5,6 +5,8 @@
 //! For example:
 //!
 //! ```rust
+//! # #[cfg(feature = "std")]
+//! # {
 //! use mmarinus::{Map, perms};
 //! use std::io::Read;
 //!
@@ -20,11 +22,14 @@
 //!     .unwrap();
 //!
 //! assert_eq!(&*map, &chunk);
+//! # }
 //! ```
 //!
 //! You can also remap an existing mapping:
 //!
 //! ```rust
+//! # #[cfg(feature = "std")]
+//! # {
 //! use mmarinus::{Map, perms};
 //! use std::io::Read;
 //!
@@ -52,11 +57,14 @@
 //!     *i = 255;
 //! }
 //! assert_eq!(&*map, &[255; 32]);
+//! # }
 //! ```
 //!
 //! Alternatively, you can just change the permissions:
 //!
 //! ```rust
+//! # #[cfg(feature = "std")]
+//! # {
 //! use mmarinus::{Map, perms};
 //! use std::io::Read;
 //!
@@ -80,16 +88,35 @@
 //!     *i = 255;
 //! }
 //! assert_eq!(&*map, &[255; 32]);
+//! # }
 //! ```
 //!
 //! Mapping a whole file into memory is easy:
 //!
 //! ```rust
+//! # #[cfg(feature = "std")]
+//! # {
 //! use mmarinus::{Map, Private, perms};
 //!
 //! let map = Map::load("/etc/hosts", Private, perms::Read).unwrap();
+//! # }
 //! ```
 
+//!
+//! # Features
+//!
+//! The `std` feature is enabled by default. Without it, the crate does not
+//! depend on `std`: `Map`, its builder and the permissions are built on `core`
+//! alone. Errors are reported as `Errno` either way; with the feature, it
+//! converts to and from `std::io::Error`.
+//! Anything which needs files, paths, threads or allocation (for example,
+//! `Map::load()`, `MapCursor` and the allocators) requires the `std` feature.
+//!
+//! The `tracking` feature (which implies `std`) keeps a registry of the live
+//! mappings, which can be inspected and limited with a budget. See the
+//! `tracking` module.
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 #![forbid(clippy::expect_used, clippy::panic)]
 #![warn(
     rust_2018_idioms,
@@ -99,10 +126,55 @@
     missing_docs
 )]
 
+#[cfg(feature = "std")]
+mod arena;
 mod builder;
+#[cfg(feature = "std")]
+mod cursor;
+mod errno;
 mod error;
+mod flags;
 mod map;
+mod prefault;
+#[cfg(all(target_os = "linux", feature = "std"))]
+mod seal;
+#[cfg(target_os = "linux")]
+mod secret;
+#[cfg(all(target_os = "linux", feature = "std"))]
+mod snapshot;
 
-pub use error::Error;
-pub use map::{Map, Private, Shared};
+#[cfg(feature = "std")]
+pub use arena::Arena;
+#[cfg(feature = "std")]
+pub use cursor::MapCursor;
+pub use errno::Errno;
+pub use error::{Error, Op, Operation, Reason};
+pub use flags::Flags;
+pub use kinds::{Private, Shared};
+pub use map::Map;
+#[cfg(target_os = "linux")]
+pub use map::Placement;
+pub use prefault::Access;
+#[cfg(all(target_os = "linux", feature = "std"))]
+pub use seal::SealedMap;
+#[cfg(target_os = "linux")]
+pub use secret::SecretMap;
+#[cfg(all(target_os = "linux", feature = "std"))]
+pub mod alloc;
+#[cfg(feature = "std")]
+pub mod appender;
+pub mod backend;
+#[cfg(all(target_os = "linux", feature = "std"))]
+pub mod elf;
+pub mod kinds;
+#[cfg(all(target_os = "linux", feature = "std"))]
+pub mod numa;
+pub mod observer;
+pub mod page;
 pub mod perms;
+#[cfg(all(target_os = "linux", feature = "std"))]
+pub mod sigbus;
+#[cfg(all(target_os = "linux", feature = "std"))]
+pub mod stack;
+#[cfg(feature = "tracking")]
+pub mod tracking;
@@ -1,20 +1,54 @@
+use super::backend::{Backend, Libc};
 use super::builder::{Address, Builder, Destination, Size};
+use super::errno;
+use super::error::{Op, Operation};
+use super::kinds::{self, Private, Shared};
+use super::observer::{self, Event};
+use super::page::{PageSize, Tier};
 use super::{perms, Error};
 
-use std::convert::{TryFrom, TryInto};
+#[cfg(feature = "tracking")]
+use super::tracking;
+
+use core::marker::PhantomData;
+use core::mem::{forget, size_of};
+use core::ops::Range;
+use core::ptr::{read_volatile, write_volatile};
+use core::slice::{from_raw_parts, from_raw_parts_mut};
+
+#[cfg(feature = "std")]
+use std::convert::TryInto;
+#[cfg(feature = "std")]
 use std::io::ErrorKind;
-use std::marker::PhantomData;
-use std::mem::forget;
+#[cfg(feature = "std")]
 use std::path::Path;
-use std::slice::{from_raw_parts, from_raw_parts_mut};
 
 pub trait Kind {
+    /// The kind, if it is known at compile time
+    const KNOWN: Option<libc::c_int> = None;
+
     fn kind(self) -> libc::c_int;
 }
 
+pub trait KnownKind: Kind {
+    const KIND: libc::c_int;
+}
+
+impl<K: KnownKind> Kind for K {
+    const KNOWN: Option<libc::c_int> = Some(K::KIND);
+
+    #[inline]
+    fn kind(self) -> libc::c_int {
+        Self::KIND
+    }
+}
+
 pub trait Safe: Kind {}
 
 pub trait Type {
+    /// The permissions, if they are known at compile time
+    const KNOWN: Option<libc::c_int> = None;
+
     fn perms(self) -> libc::c_int;
 }
 
@@ -23,6 +57,8 @@ pub trait Known: Type {
 }
 
 impl<T: Known> Type for T {
+    const KNOWN: Option<libc::c_int> = Some(T::VALUE);
+
     fn perms(self) -> libc::c_int {
         Self::VALUE
     }
@@ -34,51 +70,29 @@ pub trait Writeable: Known {}
 
 pub trait Executable: Known {}
 
-/// Indicates a private mapping
-#[derive(Debug)]
-pub struct Private;
-
-impl Safe for Private {}
-
-impl Kind for Private {
-    #[inline]
-    fn kind(self) -> libc::c_int {
-        libc::MAP_PRIVATE
-    }
-}
-
-/// Indicates a shared mapping
-#[derive(Debug)]
-pub struct Shared;
-
-impl Kind for Shared {
-    #[inline]
-    fn kind(self) -> libc::c_int {
-        libc::MAP_SHARED
-    }
-}
-
 /// A smart pointer to a mapped region of memory
 ///
 /// When this reference is destroyed, `munmap()` will be called on the region.
+/// The system calls are made through the backend `B` (see the `backend`
+/// module).
 #[derive(Debug)]
-pub struct Map<T: Type, K: Kind = Private> {
+pub struct Map<T: Type, K: Kind = Private, B: Backend = Libc> {
     pub(crate) addr: usize,
     pub(crate) size: usize,
-    pub(crate) data: PhantomData<(T, K)>,
+    pub(crate) page: PageSize,
+    pub(crate) tier: Tier,
+    pub(crate) data: PhantomData<(T, K, B)>,
 }
 
-impl<T: Type, K: Kind> Drop for Map<T, K> {
+impl<T: Type, K: Kind, B: Backend> Drop for Map<T, K, B> {
     fn drop(&mut self) {
         if self.size > 0 {
-            unsafe {
-                libc::munmap(self.addr as *mut _, self.size);
-            }
+            let _ = self.release();
         }
     }
 }
 
-impl<K: Safe, T: Readable> std::ops::Deref for Map<T, K> {
+impl<K: Safe, T: Readable, B: Backend> core::ops::Deref for Map<T, K, B> {
     type Target = [u8];
 
     #[inline]
@@ -87,33 +101,50 @@ impl<K: Safe, T: Readable> std::ops::Deref for Map<T, K> {
     }
 }
 
-impl<K: Safe, T: Readable + Writeable> std::ops::DerefMut for Map<T, K> {
+impl<K: Safe, T: Readable + Writeable, B: Backend> core::ops::DerefMut for Map<T, K, B> {
     #[inline]
     fn deref_mut(&mut self) -> &mut [u8] {
         unsafe { from_raw_parts_mut(self.addr as *mut u8, self.size) }
     }
 }
 
-impl<K: Safe, T: Readable> AsRef<[u8]> for Map<T, K> {
+impl<K: Safe, T: Readable, B: Backend> AsRef<[u8]> for Map<T, K, B> {
     #[inline]
     fn as_ref(&self) -> &[u8] {
         self
     }
 }
 
-impl<K: Safe, T: Readable + Writeable> AsMut<[u8]> for Map<T, K> {
+impl<K: Safe, T: Readable + Writeable, B: Backend> AsMut<[u8]> for Map<T, K, B> {
     #[inline]
     fn as_mut(&mut self) -> &mut [u8] {
         &mut *self
     }
 }
 
-impl<K: Kind, T: Known> From<Map<T, K>> for Map<perms::Unknown, K> {
+impl<K: Kind, T: Known, B: Backend> From<Map<T, K, B>> for Map<perms::Unknown, K, B> {
+    #[inline]
+    fn from(value: Map<T, K, B>) -> Map<perms::Unknown, K, B> {
+        let map = Map {
+            addr: value.addr,
+            size: value.size,
+            page: value.page,
+            tier: value.tier,
+            data: PhantomData,
+        };
+        forget(value);
+        map
+    }
+}
+
+impl<T: Type, K: KnownKind, B: Backend> From<Map<T, K, B>> for Map<T, kinds::Unknown, B> {
     #[inline]
-    fn from(value: Map<T, K>) -> Map<perms::Unknown, K> {
+    fn from(value: Map<T, K, B>) -> Map<T, kinds::Unknown, B> {
         let map = Map {
             addr: value.addr,
             size: value.size,
+            page: value.page,
+            tier: value.tier,
             data: PhantomData,
         };
         forget(value);
@@ -121,6 +152,145 @@ impl<K: Kind, T: Known> From<Map<T, K>> for Map<perms::Unknown, K> {
     }
 }
 
+/// Validates that `len` bytes at `offset` fit within a mapping of `size` bytes
+fn range(size: usize, offset: usize, len: usize) -> errno::Result<Range<usize>> {
+    match offset.checked_add(len) {
+        Some(end) if end <= size => Ok(offset..end),
+        _ => Err(errno::from_raw(libc::EINVAL)),
+    }
+}
+
+/// Copies bytes out of mapped memory using volatile reads
+///
+/// # Safety
+///
+/// The memory at `src` must be mapped and readable for `dst.len()` bytes.
+pub(crate) unsafe fn copy_out(dst: &mut [u8], src: *const u8) {
+    const WORD: usize = size_of::<usize>();
+
+    let mut i = 0;
+    while i < dst.len() {
+        let ptr = src.add(i);
+        if ptr as usize % WORD == 0 && dst.len() - i >= WORD {
+            let word = read_volatile(ptr as *const usize);
+            dst[i..i + WORD].copy_from_slice(&word.to_ne_bytes());
+            i += WORD;
+        } else {
+            dst[i] = read_volatile(ptr);
+            i += 1;
+        }
+    }
+}
+
+/// Copies bytes into mapped memory using volatile writes
+///
+/// # Safety
+///
+/// The memory at `dst` must be mapped and writable for `src.len()` bytes.
+pub(crate) unsafe fn copy_in(dst: *mut u8, src: &[u8]) {
+    const WORD: usize = size_of::<usize>();
+
+    let mut i = 0;
+    while i < src.len() {
+        let ptr = dst.add(i);
+        if ptr as usize % WORD == 0 && src.len() - i >= WORD {
+            let mut word = [0u8; WORD];
+            word.copy_from_slice(&src[i..i + WORD]);
+            write_volatile(ptr as *mut usize, usize::from_ne_bytes(word));
+            i += WORD;
+        } else {
+            write_volatile(ptr, src[i]);
+            i += 1;
+        }
+    }
+}
+
+impl<T: Readable, K: Kind, B: Backend> Map<T, K, B> {
+    /// Copies bytes out of the mapping, starting at `offset`
+    ///
+    /// Unlike `Deref`, this function works for all kinds of mappings since
+    /// the bytes are copied using volatile reads rather than being exposed
+    /// as a reference. The copy is not atomic: if the memory is modified
+    /// concurrently (for example, by another process using a `Shared`
+    /// mapping) then `buf` may contain a mix of old and new data.
+    ///
+    /// Fails with `ErrorKind::InvalidInput` if the range is out of bounds.
+    ///
+    /// # Example
+    /// ```
+    /// use mmarinus::{Map, Shared, perms};
+    ///
+    /// let mut map = Map::bytes(4096)
+    ///     .anywhere()
+    ///     .anonymously()
+    ///     .with_kind(Shared)
+    ///     .with(perms::ReadWrite)
+    ///     .unwrap();
+    ///
+    /// map.copy_from(16, b"hello").unwrap();
+    ///
+    /// let mut buf = [0u8; 5];
+    /// map.copy_to(16, &mut buf).unwrap();
+    /// assert_eq!(&buf, b"hello");
+    /// ```
+    #[inline]
+    pub fn copy_to(&self, offset: usize, buf: &mut [u8]) -> errno::Result<()> {
+        let range = range(self.size, offset, buf.len())?;
+        unsafe { copy_out(buf, (self.addr + range.start) as *const u8) };
+        Ok(())
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "std"))]
+impl<T: Readable, K: Kind> Map<T, K> {
+    /// Copies bytes out of the mapping, recovering from `SIGBUS`
+    ///
+    /// This is like `Map::copy_to()` except that, if the file backing the
+    /// mapping has been truncated, the access fails with
+    /// `ErrorKind::UnexpectedEof` rather than killing the process. See the
+    /// `sigbus` module for details.
+    #[inline]
+    pub fn try_copy_to(&self, offset: usize, buf: &mut [u8]) -> std::io::Result<()> {
+        let range = range(self.size, offset, buf.len())?;
+        let src = (self.addr + range.start) as *const u8;
+        let page = self.page.bytes();
+        crate::sigbus::guard(self.addr, self.size, page, || unsafe { copy_out(buf, src) })
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "std"))]
+impl<T: Readable + Writeable, K: Kind> Map<T, K> {
+    /// Copies bytes into the mapping, recovering from `SIGBUS`
+    ///
+    /// This is like `Map::copy_from()` except that, if the file backing the
+    /// mapping has been truncated, the access fails with
+    /// `ErrorKind::UnexpectedEof` rather than killing the process. See the
+    /// `sigbus` module for details.
+    #[inline]
+    pub fn try_copy_from(&mut self, offset: usize, buf: &[u8]) -> std::io::Result<()> {
+        let range = range(self.size, offset, buf.len())?;
+        let dst = (self.addr + range.start) as *mut u8;
+        let page = self.page.bytes();
+        crate::sigbus::guard(self.addr, self.size, page, || unsafe { copy_in(dst, buf) })
+    }
+}
+
+impl<T: Writeable, K: Kind, B: Backend> Map<T, K, B> {
+    /// Copies bytes into the mapping, starting at `offset`
+    ///
+    /// Unlike `DerefMut`, this function works for all kinds of mappings
+    /// since the bytes are copied using volatile writes.
+    ///
+    /// Fails with `ErrorKind::InvalidInput` if the range is out of bounds.
+    #[inline]
+    pub fn copy_from(&mut self, offset: usize, buf: &[u8]) -> errno::Result<()> {
+        let range = range(self.size, offset, buf.len())?;
+        unsafe { copy_in((self.addr + range.start) as *mut u8, buf) };
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
 impl<T: Type, K: Kind> Map<T, K> {
     /// Maps a whole file into memory
     ///
@@ -136,7 +306,9 @@ impl<T: Type, K: Kind> Map<T, K> {
             .with_kind(kind)
             .with(perms)
     }
+}
 
+impl<T: Type, K: Kind, B: Backend> Map<T, K, B> {
     /// Gets the address of the mapping
     #[inline]
     pub fn addr(&self) -> usize {
@@ -149,6 +321,25 @@ impl<T: Type, K: Kind> Map<T, K> {
         self.size
     }
 
+    /// Gets the size of the pages backing the mapping
+    ///
+    /// This is the base page size unless the mapping was created with huge
+    /// pages. The mapping can only be split at multiples of this size.
+    #[inline]
+    pub fn page_size(&self) -> PageSize {
+        self.page
+    }
+
+    /// Gets the kind of pages backing the mapping
+    ///
+    /// This is mostly useful for mappings created with
+    /// `with_huge_pages_or_fallback()`, to find out which kind of pages
+    /// the mapping ended up with.
+    #[inline]
+    pub fn tier(&self) -> Tier {
+        self.tier
+    }
+
     /// Changes the settings of an existing mapping
     ///
     /// Upon success, the new mapping "steals" the mapping from the old `Map`
@@ -169,17 +360,36 @@ impl<T: Type, K: Kind> Map<T, K> {
     /// Upon success, the new mapping "steals" the mapping from the old `Map`
     /// instance. Using the old instance is a logic error, but is safe.
     #[inline]
-    pub fn reprotect<U: Type>(self, perms: U) -> Result<Map<U, K>, Error<Self>> {
-        if unsafe { libc::mprotect(self.addr as _, self.size, perms.perms()) } != 0 {
+    pub fn reprotect<U: Type>(self, perms: U) -> Result<Map<U, K, B>, Error<Self>> {
+        let prot = perms.perms();
+        let ret = unsafe { B::mprotect(self.addr, self.size, prot) };
+        if let Some(observer) = observer::get::<B>() {
+            observer.on_protect(&self.event(Some(prot), ret.as_ref().err()));
+        }
+
+        if let Err(err) = ret {
             return Err(Error {
+                err: err.with(Operation {
+                    op: Op::Protect,
+                    addr: self.addr,
+                    size: self.size,
+                    prot,
+                    flags: 0,
+                }),
                 map: self,
-                err: std::io::Error::last_os_error(),
             });
         }
 
+        #[cfg(feature = "tracking")]
+        if B::HOOKS {
+            tracking::update(self.addr, |r| r.prot = prot);
+        }
+
         let map = Map {
             addr: self.addr,
             size: self.size,
+            page: self.page,
+            tier: self.tier,
             data: PhantomData,
         };
 
@@ -189,7 +399,8 @@ impl<T: Type, K: Kind> Map<T, K> {
 
     /// Split a mapping at the specified offset.
     ///
-    /// The split address MUST be page-aligned or this call will fail.
+    /// The split address MUST be aligned to `Map::page_size()` or this call
+    /// will fail. For hugetlb mappings, this is the huge page size.
     ///
     /// # Example
     /// ```
@@ -208,35 +419,61 @@ impl<T: Type, K: Kind> Map<T, K> {
     /// assert_eq!(r.size(), SIZE);
     /// ```
     pub fn split(self, offset: usize) -> Result<(Self, Self), Error<Self>> {
-        if let Ok(psize) = usize::try_from(unsafe { libc::sysconf(libc::_SC_PAGESIZE) }) {
-            let addr = self.addr + offset;
-            if offset <= self.size && addr % psize == 0 {
+        let addr = self.addr + offset;
+        let err = match offset <= self.size && self.page.is_aligned(addr) {
+            true => None,
+            false => Some(errno::from_raw(libc::EINVAL)),
+        };
+
+        if let Some(observer) = observer::get::<B>() {
+            observer.on_split(&self.event(None, err.as_ref()), offset);
+        }
+
+        let err = match err {
+            Some(err) => err,
+            None => {
                 let l = Self {
                     addr: self.addr,
                     size: offset,
+                    page: self.page,
+                    tier: self.tier,
                     data: PhantomData,
                 };
 
                 let r = Self {
                     addr,
                     size: self.size - offset,
+                    page: self.page,
+                    tier: self.tier,
                     data: PhantomData,
                 };
 
+                #[cfg(feature = "tracking")]
+                if B::HOOKS {
+                    tracking::split(self.addr, offset);
+                }
+
                 forget(self);
                 return Ok((l, r));
             }
-        }
+        };
 
         Err(Error {
+            err: err.with(Operation {
+                op: Op::Split,
+                addr,
+                size: self.size,
+                prot: 0,
+                flags: 0,
+            }),
             map: self,
-            err: std::io::Error::from_raw_os_error(libc::EINVAL),
         })
     }
 
     /// Split a mapping at the specified address.
     ///
-    /// The address (`at`) MUST be page-aligned or this call will fail.
+    /// The address (`at`) MUST be aligned to `Map::page_size()` or this call
+    /// will fail.
     ///
     /// # Example
     /// ```
@@ -264,6 +501,262 @@ impl<T: Type, K: Kind> Map<T, K> {
 
         self.split(offset)
     }
+
+    /// Releases ownership of the mapping without unmapping it
+    ///
+    /// Returns the address and the size of the mapping. The caller is
+    /// responsible for unmapping it, for example by passing it back to
+    /// `Map::from_raw()`. The page size is not part of the result, so a
+    /// mapping of huge pages must be passed back to
+    /// `Map::from_raw_with_page()` with its `Map::page_size()` instead.
+    #[inline]
+    pub fn into_raw(self) -> (usize, usize) {
+        let raw = (self.addr, self.size);
+        forget(self);
+        raw
+    }
+
+    /// Takes ownership of an existing mapping of base pages
+    ///
+    /// This is `Map::from_raw_with_page()` with `PageSize::base()`.
+    ///
+    /// # Safety
+    ///
+    /// See `Map::from_raw_with_page()`. Additionally, the mapping must use
+    /// base pages.
+    #[inline]
+    pub unsafe fn from_raw(addr: usize, size: usize) -> Self {
+        Self::from_raw_with_page(addr, size, PageSize::base())
+    }
+
+    /// Takes ownership of an existing mapping of pages of `page` bytes
+    ///
+    /// # Safety
+    ///
+    /// The range must be mapped with permissions `T` and kind `K`, with
+    /// pages of the given size, and must not be owned by anything else
+    /// (such as another `Map`), since it is unmapped when the `Map` is
+    /// dropped. This is the case for the values returned by
+    /// `Map::into_raw()`.
+    #[inline]
+    pub unsafe fn from_raw_with_page(addr: usize, size: usize, page: PageSize) -> Self {
+        let tier = match page == PageSize::base() {
+            true => Tier::Base,
+            false => Tier::Huge,
+        };
+
+        Self {
+            addr,
+            size,
+            page,
+            tier,
+            data: PhantomData,
+        }
+    }
+
+    /// Unmaps the mapping
+    ///
+    /// This is what happens when a `Map` is dropped, except that failures
+    /// are reported instead of being ignored. On failure, the mapping is
+    /// returned with the error.
+    ///
+    /// ```rust
+    /// use mmarinus::{Map, perms};
+    ///
+    /// let map = Map::bytes(4096)
+    ///     .anywhere()
+    ///     .anonymously()
+    ///     .with(perms::Read)
+    ///     .unwrap();
+    ///
+    /// map.unmap().unwrap();
+    /// ```
+    pub fn unmap(self) -> Result<(), Error<Self>> {
+        let ret = match self.size {
+            0 => Ok(()),
+            _ => self.release(),
+        };
+
+        match ret {
+            Ok(()) => {
+                forget(self);
+                Ok(())
+            }
+
+            Err(err) => Err(Error {
+                err: err.with(Operation {
+                    op: Op::Unmap,
+                    addr: self.addr,
+                    size: self.size,
+                    prot: 0,
+                    flags: 0,
+                }),
+                map: self,
+            }),
+        }
+    }
+
+    /// Unmaps the mapping, which must not be used afterwards
+    fn release(&self) -> errno::Result<()> {
+        let ret = unsafe { B::munmap(self.addr, self.size) };
+
+        #[cfg(feature = "tracking")]
+        if B::HOOKS && ret.is_ok() {
+            tracking::unmap(self.addr, self.size);
+        }
+
+        if let Some(observer) = observer::get::<B>() {
+            observer.on_unmap(&self.event(None, ret.as_ref().err()));
+        }
+
+        ret
+    }
+
+    /// Describes the mapping for the observer
+    ///
+    /// Without `prot`, the permissions are those of the type, if known.
+    #[inline]
+    pub(crate) fn event<'a>(
+        &self,
+        prot: Option<libc::c_int>,
+        err: Option<&'a errno::OsError>,
+    ) -> Event<'a> {
+        Event {
+            addr: self.addr,
+            size: self.size,
+            prot: prot.or(T::KNOWN),
+            kind: K::KNOWN,
+            err,
+        }
+    }
+}
+
+impl<T: Readable + Writeable, K: Safe, B: Backend> Map<T, K, B> {
+    /// Leaks the mapping, returning its contents for the rest of the program
+    ///
+    /// The mapping is never unmapped. This is useful for handing memory to
+    /// code which expects to keep it forever.
+    ///
+    /// ```rust
+    /// use mmarinus::{Map, perms};
+    ///
+    /// let buf: &'static mut [u8] = Map::bytes(4096)
+    ///     .anywhere()
+    ///     .anonymously()
+    ///     .with(perms::ReadWrite)
+    ///     .unwrap()
+    ///     .leak();
+    ///
+    /// buf[0] = 1;
+    /// ```
+    #[inline]
+    pub fn leak(self) -> &'static mut [u8] {
+        let (addr, size) = self.into_raw();
+        unsafe { from_raw_parts_mut(addr as *mut u8, size) }
+    }
+}
+
+/// Where to place a new mapping
+#[cfg(target_os = "linux")]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Placement {
+    /// Anywhere in valid memory (see `Builder::anywhere()`)
+    Anywhere,
+
+    /// At the specified address, which must be free (see `Builder::at()`)
+    At(usize),
+
+    /// Near the specified address (see `Builder::near()`)
+    Near(usize),
+}
+
+#[cfg(target_os = "linux")]
+impl<T: Type, B: Backend> Map<T, Shared, B> {
+    /// Creates another mapping of the same pages
+    ///
+    /// The alias is a separate mapping with its own permissions which shares
+    /// its pages with this mapping: writes through either mapping are visible
+    /// through the other. Both mappings can be dropped independently. This
+    /// works for anonymous and file-backed shared mappings alike, since the
+    /// alias is created with `mremap()` and an old size of zero.
+    ///
+    /// ```rust
+    /// use mmarinus::{Map, Placement, Shared, perms};
+    ///
+    /// let mut map = Map::bytes(4096)
+    ///     .anywhere()
+    ///     .anonymously()
+    ///     .with_kind(Shared)
+    ///     .with(perms::ReadWrite)
+    ///     .unwrap();
+    ///
+    /// let view = map.alias(perms::Read, Placement::Anywhere).unwrap();
+    /// map.copy_from(0, b"hello").unwrap();
+    ///
+    /// let mut buf = [0u8; 5];
+    /// view.copy_to(0, &mut buf).unwrap();
+    /// assert_eq!(&buf, b"hello");
+    /// ```
+    pub fn alias<U: Type>(
+        &self,
+        perms: U,
+        placement: Placement,
+    ) -> Result<Map<U, Shared, B>, Error<()>> {
+        let builder = Map::bytes(self.size);
+        let builder = match placement {
+            Placement::Anywhere => builder.anywhere(),
+            Placement::At(addr) => builder.at(addr),
+            Placement::Near(addr) => builder.near(addr),
+        };
+
+        // Reserve the address range first, so that the placement has the
+        // same semantics as for new mappings.
+        let reserved = builder
+            .anonymously()
+            .with_backend::<B>()
+            .with(perms::None)?;
+
+        let flags = libc::MREMAP_MAYMOVE | libc::MREMAP_FIXED;
+        let addr = reserved.addr();
+        let ret = unsafe { B::mremap(self.addr, 0, self.size, flags, addr) };
+        if let Some(observer) = observer::get::<B>() {
+            let event = Event {
+                addr,
+                ..self.event(None, ret.as_ref().err())
+            };
+
+            observer.on_remap(&event);
+        }
+
+        if let Err(err) = ret {
+            return Err(Error {
+                map: (),
+                err: err.with(Operation {
+                    op: Op::Remap,
+                    addr,
+                    size: self.size,
+                    prot: 0,
+                    flags,
+                }),
+            });
+        }
+
+        #[cfg(feature = "tracking")]
+        if B::HOOKS {
+            tracking::update(addr, |r| r.kind = libc::MAP_SHARED);
+        }
+
+        forget(reserved);
+        let map: Self = Map {
+            addr,
+            size: self.size,
+            page: self.page,
+            tier: self.tier,
+            data: PhantomData,
+        };
+
+        map.reprotect(perms).map_err(|e| e.map_inner(drop))
+    }
 }
 
 impl Map<perms::Unknown, Shared> {
@@ -276,7 +769,16 @@ impl Map<perms::Unknown, Shared> {
 
 #[cfg(test)]
 mod tests {
-    use crate::{perms, Map};
+    #[cfg(feature = "std")]
+    use crate::backend::{Mock, Syscall};
+    #[cfg(feature = "std")]
+    use crate::page::HugePageSize;
+    use crate::page::{PageSize, Tier};
+    #[cfg(feature = "std")]
+    use crate::Flags;
+    use crate::{kinds, perms, Map, Placement, Shared};
+    #[cfg(feature = "std")]
+    use std::io::{ErrorKind, Read};
 
     #[test]
     fn zero_split() {
@@ -331,4 +833,418 @@ mod tests {
             Ok(map) => assert_eq!(map.size(), SIZE),
         }
     }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn flags() {
+        Mock::reset();
+
+        let map = Map::bytes(4096)
+            .anywhere()
+            .anonymously()
+            .with_flags(Flags::POPULATE | Flags::NORESERVE)
+            .with_flags(Flags::STACK)
+            .with_backend::<Mock>()
+            .with(perms::ReadWrite)
+            .unwrap();
+
+        assert_eq!(&*map, &[0; 4096]);
+
+        let calls = Mock::calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].syscall, Syscall::Mmap);
+        assert_eq!(calls[0].arg, libc::PROT_READ | libc::PROT_WRITE);
+
+        let flags = libc::MAP_POPULATE | libc::MAP_NORESERVE | libc::MAP_STACK;
+        assert_eq!(calls[0].flags & flags, flags);
+        assert_ne!(calls[0].flags & libc::MAP_ANONYMOUS, 0);
+
+        // Anonymous mappings may grow downwards.
+        let map = Map::bytes(4096)
+            .anywhere()
+            .anonymously()
+            .with_flags(Flags::GROWSDOWN)
+            .with_backend::<Mock>()
+            .with(perms::ReadWrite)
+            .unwrap();
+
+        drop(map);
+        let calls = Mock::calls();
+        assert_ne!(calls[0].flags & libc::MAP_GROWSDOWN, 0);
+
+        // MAP_SYNC is only honoured along with MAP_SHARED_VALIDATE, which
+        // fails on files which do not support it (i.e. outside of DAX).
+        let mut file = file("sync", 4096);
+
+        let _ = Map::bytes(4096)
+            .anywhere()
+            .from(&mut file, 0)
+            .with_kind(Shared)
+            .with_flags(Flags::SYNC)
+            .with_backend::<Mock>()
+            .with(perms::Read);
+
+        let calls = Mock::calls();
+        let flags = libc::MAP_SYNC | libc::MAP_SHARED_VALIDATE;
+        assert_eq!(calls[0].syscall, Syscall::Mmap);
+        assert_eq!(calls[0].flags & flags, flags);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn flags_invalid() {
+        Mock::reset();
+
+        let mut file = file("flags", 4096);
+
+        // Only anonymous mappings may grow downwards.
+        let err = Map::bytes(4096)
+            .anywhere()
+            .from(&mut file, 0)
+            .with_flags(Flags::GROWSDOWN)
+            .with_backend::<Mock>()
+            .with(perms::Read)
+            .unwrap_err();
+        assert_eq!(err.err.kind(), ErrorKind::InvalidInput);
+
+        let err = Map::bytes(4096)
+            .anywhere()
+            .from(&mut file, 0)
+            .with_kind(Shared)
+            .with_flags(Flags::GROWSDOWN)
+            .with_backend::<Mock>()
+            .with(perms::Read)
+            .unwrap_err();
+        assert_eq!(err.err.kind(), ErrorKind::InvalidInput);
+
+        // Only shared file mappings may be synchronous.
+        let err = Map::bytes(4096)
+            .anywhere()
+            .from(&mut file, 0)
+            .with_flags(Flags::SYNC)
+            .with_backend::<Mock>()
+            .with(perms::Read)
+            .unwrap_err();
+        assert_eq!(err.err.kind(), ErrorKind::InvalidInput);
+
+        let err = Map::bytes(4096)
+            .anywhere()
+            .anonymously()
+            .with_flags(Flags::SYNC)
+            .with_backend::<Mock>()
+            .with(perms::Read)
+            .unwrap_err();
+        assert_eq!(err.err.kind(), ErrorKind::InvalidInput);
+
+        let err = Map::bytes(4096)
+            .anywhere()
+            .anonymously()
+            .with_kind(Shared)
+            .with_flags(Flags::SYNC)
+            .with_backend::<Mock>()
+            .with(perms::Read)
+            .unwrap_err();
+        assert_eq!(err.err.kind(), ErrorKind::InvalidInput);
+
+        // The flags are rejected before anything is mapped.
+        assert!(Mock::calls().is_empty());
+    }
+
+    /// Creates an unlinked temporary file of `size` bytes
+    #[cfg(feature = "std")]
+    fn file(name: &str, size: u64) -> std::fs::File {
+        let name = format!("mmarinus-map-{}-{}", name, std::process::id());
+        let path = std::env::temp_dir().join(name);
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+        file.set_len(size).unwrap();
+        file
+    }
+
+    #[test]
+    fn copy() {
+        let mut map = Map::bytes(4096)
+            .anywhere()
+            .anonymously()
+            .with_kind(Shared)
+            .with(perms::ReadWrite)
+            .unwrap();
+
+        let data: Vec<u8> = (0..=255).collect();
+        map.copy_from(3, &data).unwrap();
+
+        let mut buf = [0u8; 256];
+        map.copy_to(3, &mut buf).unwrap();
+        assert_eq!(&buf[..], &data[..]);
+
+        let err = map.copy_to(4096 - 255, &mut buf).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        let err = map.copy_from(usize::MAX, &data).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn shared_validate() {
+        let mut hosts = std::fs::File::open("/etc/hosts").unwrap();
+
+        let mut chunk = [0u8; 16];
+        hosts.read_exact(&mut chunk).unwrap();
+
+        let map = Map::bytes(16)
+            .anywhere()
+            .from(&mut hosts, 0)
+            .with_kind(kinds::SharedValidate)
+            .with(perms::Read)
+            .unwrap();
+
+        let mut buf = [0u8; 16];
+        map.copy_to(0, &mut buf).unwrap();
+        assert_eq!(buf, chunk);
+    }
+
+    #[test]
+    fn droppable() {
+        let ret = Map::bytes(4096)
+            .anywhere()
+            .anonymously()
+            .with_kind(kinds::Droppable)
+            .with(perms::ReadWrite);
+
+        match ret {
+            // Droppable mappings require Linux 6.11
+            Err(e) => assert_eq!(e.err.raw_os_error(), Some(libc::EINVAL)),
+            Ok(mut map) => {
+                map.copy_from(0, b"droppable").unwrap();
+
+                let mut buf = [0u8; 9];
+                map.copy_to(0, &mut buf).unwrap();
+                assert!(&buf == b"droppable" || buf == [0; 9]);
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_kind() {
+        let map: Map<perms::Read, kinds::Unknown> = Map::bytes(4096)
+            .anywhere()
+            .anonymously()
+            .with_kind(kinds::Unknown(libc::MAP_SHARED))
+            .with(perms::Read)
+            .unwrap();
+        assert_eq!(map.size(), 4096);
+
+        let map = Map::bytes(4096)
+            .anywhere()
+            .anonymously()
+            .with_kind(Shared)
+            .with(perms::Read)
+            .unwrap();
+
+        let map: Map<perms::Read, kinds::Unknown> = map.into();
+        assert_eq!(map.size(), 4096);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn huge_size() {
+        const SIZE: usize = 4 * 1024 * 1024;
+
+        // The system might not support huge pages at all.
+        if PageSize::default_huge().is_err() {
+            return;
+        }
+
+        let ret = Map::bytes(SIZE)
+            .anywhere()
+            .anonymously()
+            .with_huge_page_size(HugePageSize::Default)
+            .with(perms::Read);
+
+        match ret {
+            // System might not have huge pages reserved by the admin
+            Err(e) => assert_eq!(e.err.kind(), ErrorKind::OutOfMemory),
+            Ok(map) => assert_eq!(map.size(), SIZE),
+        }
+    }
+
+    #[test]
+    fn page_size() {
+        let map = Map::bytes(1)
+            .anywhere()
+            .anonymously()
+            .with(perms::Read)
+            .unwrap();
+
+        assert_eq!(map.page_size(), PageSize::base());
+        assert_eq!(map.tier(), Tier::Base);
+        assert_eq!(map.size(), 1);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn huge_split() {
+        // The system might not support huge pages at all.
+        if PageSize::default_huge().is_err() {
+            return;
+        }
+
+        let ret = Map::bytes(1)
+            .anywhere()
+            .anonymously()
+            .with_huge_page_size(HugePageSize::Default)
+            .with(perms::Read);
+
+        let map = match ret {
+            // System might not have huge pages reserved by the admin
+            Err(e) => return assert_eq!(e.err.kind(), ErrorKind::OutOfMemory),
+            Ok(map) => map,
+        };
+
+        let page = map.page_size();
+        assert_eq!(page, PageSize::default_huge().unwrap());
+        assert_eq!(map.size(), page.bytes());
+
+        let (addr, size) = map.into_raw();
+        let map = unsafe { Map::<perms::Read>::from_raw_with_page(addr, size, page) };
+        assert_eq!(map.page_size(), page);
+        assert_eq!(map.tier(), Tier::Huge);
+
+        let map = map.split(PageSize::base().bytes()).unwrap_err().map;
+        let (l, r) = map.split(page.bytes()).unwrap();
+        assert_eq!(l.size(), page.bytes());
+        assert_eq!(r.size(), 0);
+    }
+
+    #[test]
+    fn huge_fallback() {
+        const SIZE: usize = 4 * 1024 * 1024;
+
+        // No system has 1 TiB huge pages.
+        let mut map = Map::bytes(SIZE)
+            .anywhere()
+            .anonymously()
+            .with_huge_pages_or_fallback(40)
+            .with(perms::ReadWrite)
+            .unwrap();
+
+        assert_ne!(map.tier(), Tier::Huge);
+        assert_eq!(map.page_size(), PageSize::base());
+        assert_eq!(map.size(), SIZE);
+        if map.tier() == Tier::Transparent {
+            assert_eq!(map.addr() % (2 << 20), 0);
+        }
+
+        map[SIZE - 1] = 1;
+        let (l, r) = map.split(SIZE / 2).unwrap();
+        assert_eq!(l.size(), r.size());
+    }
+
+    #[test]
+    fn alias() {
+        let mut map = Map::bytes(8192)
+            .anywhere()
+            .anonymously()
+            .with_kind(Shared)
+            .with(perms::ReadWrite)
+            .unwrap();
+
+        let view = map.alias(perms::Read, Placement::Anywhere).unwrap();
+        assert_ne!(view.addr(), map.addr());
+        assert_eq!(view.size(), map.size());
+
+        map.copy_from(4096, b"alias").unwrap();
+        let mut buf = [0u8; 5];
+        view.copy_to(4096, &mut buf).unwrap();
+        assert_eq!(&buf, b"alias");
+
+        // The alias outlives the original mapping.
+        let mut rw = view.alias(perms::ReadWrite, Placement::Anywhere).unwrap();
+        drop(map);
+        rw.copy_from(0, b"again").unwrap();
+        view.copy_to(0, &mut buf).unwrap();
+        assert_eq!(&buf, b"again");
+
+        let err = rw
+            .alias(perms::Read, Placement::At(view.addr()))
+            .unwrap_err();
+        assert_eq!(err.reason(), crate::Reason::AddressInUse);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn alias_file() {
+        let mut file = std::fs::File::open("/etc/hosts").unwrap();
+        let mut chunk = [0u8; 16];
+        file.read_exact(&mut chunk).unwrap();
+
+        let map = Map::bytes(16)
+            .anywhere()
+            .from(&mut file, 0)
+            .with_kind(Shared)
+            .with(perms::Read)
+            .unwrap();
+
+        let near = map.addr() + (1 << 30);
+        let view = map.alias(perms::Read, Placement::Near(near)).unwrap();
+        drop(map);
+
+        let mut buf = [0u8; 16];
+        view.copy_to(0, &mut buf).unwrap();
+        assert_eq!(buf, chunk);
+    }
+
+    #[test]
+    fn raw() {
+        let mut map = Map::bytes(4096)
+            .anywhere()
+            .anonymously()
+            .with(perms::ReadWrite)
+            .unwrap();
+
+        map[7] = 7;
+        let (addr, size) = map.into_raw();
+        assert_eq!(size, 4096);
+
+        let map = unsafe { Map::<perms::Read>::from_raw(addr, size) };
+        assert_eq!(map[7], 7);
+        assert_eq!(map.page_size(), PageSize::base());
+        map.unmap().unwrap();
+
+        let leaked = Map::bytes(16)
+            .anywhere()
+            .anonymously()
+            .with(perms::ReadWrite)
+            .unwrap()
+            .leak();
+        leaked[15] = 1;
+        assert_eq!(leaked.len(), 16);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn unmap() {
+        let map = Map::bytes(4096)
+            .anywhere()
+            .anonymously()
+            .with_backend::<Mock>()
+            .with(perms::Read)
+            .unwrap();
+
+        Mock::fail(Syscall::Munmap, libc::EINVAL);
+        let err = map.unmap().unwrap_err();
+        assert_eq!(err.op().unwrap().op, crate::Op::Unmap);
+        assert_eq!(err.err.raw_os_error(), Some(libc::EINVAL));
+
+        Mock::reset();
+        err.map.unmap().unwrap();
+        assert_eq!(Mock::calls().len(), 1);
+    }
 }
@@ -0,0 +1,280 @@
+// SPDX-License-Identifier: Apache-2.0
+//! NUMA memory policies for mappings
+//!
+//! On systems with more than one memory node, the kernel can be told which
+//! nodes should back the pages of a mapping. On systems with a single node,
+//! all policies are accepted for node 0.
+//!
+//! ```rust
+//! use mmarinus::{Map, perms};
+//! use mmarinus::numa::{BindFlags, Policy};
+//!
+//! let map = Map::bytes(4096)
+//!     .anywhere()
+//!     .anonymously()
+//!     .with(perms::ReadWrite)
+//!     .unwrap();
+//!
+//! # let ret =
+//! map.bind(&[0], Policy::Bind, BindFlags::empty())
+//! # ;
+//! # // Containers may forbid changing the memory policy.
+//! # assert!(ret.is_ok() || ret.unwrap_err().raw_os_error() == Some(libc::EPERM));
+//! ```
+
+use super::map::{Kind, Type};
+use super::Map;
+
+use std::convert::TryFrom;
+use std::ops::BitOr;
+
+// Not exported by libc for all targets; see `include/uapi/linux/mempolicy.h`.
+const MPOL_MF_STRICT: libc::c_uint = 1 << 0;
+const MPOL_MF_MOVE: libc::c_uint = 1 << 1;
+const MPOL_MF_MOVE_ALL: libc::c_uint = 1 << 2;
+
+const BITS: usize = libc::c_ulong::BITS as usize;
+
+/// A NUMA memory policy
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Policy {
+    /// Allocates pages only from the given nodes (`MPOL_BIND`)
+    Bind,
+
+    /// Prefers to allocate pages from the given node (`MPOL_PREFERRED`)
+    ///
+    /// Only one node may be given. If none are given, this is the same as
+    /// `Policy::Local`.
+    Preferred,
+
+    /// Allocates pages round-robin from the given nodes (`MPOL_INTERLEAVE`)
+    Interleave,
+
+    /// Allocates pages from the node of the faulting CPU (`MPOL_LOCAL`)
+    ///
+    /// No nodes may be given.
+    Local,
+}
+
+impl Policy {
+    fn mode(self) -> libc::c_int {
+        match self {
+            Self::Bind => libc::MPOL_BIND,
+            Self::Preferred => libc::MPOL_PREFERRED,
+            Self::Interleave => libc::MPOL_INTERLEAVE,
+            Self::Local => libc::MPOL_LOCAL,
+        }
+    }
+}
+
+/// Flags for `Map::bind()`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct BindFlags(libc::c_uint);
+
+impl BindFlags {
+    /// Fails if existing pages do not follow the policy (`MPOL_MF_STRICT`)
+    pub const STRICT: Self = Self(MPOL_MF_STRICT);
+
+    /// Moves existing pages used only by this process (`MPOL_MF_MOVE`)
+    pub const MOVE: Self = Self(MPOL_MF_MOVE);
+
+    /// Moves all existing pages (`MPOL_MF_MOVE_ALL`)
+    ///
+    /// This requires `CAP_SYS_NICE`.
+    pub const MOVE_ALL: Self = Self(MPOL_MF_MOVE_ALL);
+
+    /// Returns an empty set of flags
+    #[inline]
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Returns the raw value of the flags
+    #[inline]
+    pub const fn bits(self) -> libc::c_uint {
+        self.0
+    }
+}
+
+impl BitOr for BindFlags {
+    type Output = Self;
+
+    #[inline]
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Applies `policy` for `nodes` to `[addr, addr + size)`
+pub(crate) fn mbind(
+    addr: usize,
+    size: usize,
+    nodes: &[u32],
+    policy: Policy,
+    flags: BindFlags,
+) -> std::io::Result<()> {
+    let mut mask: Vec<libc::c_ulong> = Vec::new();
+    for node in nodes.iter().map(|n| *n as usize) {
+        if mask.len() <= node / BITS {
+            mask.resize(node / BITS + 1, 0);
+        }
+
+        mask[node / BITS] |= 1 << (node % BITS);
+    }
+
+    // The kernel ignores the last bit of the mask (see `get_nodes()`).
+    let (ptr, max) = match mask.len() {
+        0 => (std::ptr::null(), 1),
+        n => (mask.as_ptr(), n * BITS + 1),
+    };
+
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            addr,
+            size,
+            policy.mode(),
+            ptr,
+            max,
+            flags.bits(),
+        )
+    };
+
+    match ret {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+impl<T: Type, K: Kind> Map<T, K> {
+    /// Sets the NUMA memory policy of the mapping (see `mbind(2)`)
+    ///
+    /// The policy applies to pages faulted in afterwards. Pages which are
+    /// already present are only moved if `BindFlags::MOVE` or
+    /// `BindFlags::MOVE_ALL` is given.
+    #[inline]
+    pub fn bind(&self, nodes: &[u32], policy: Policy, flags: BindFlags) -> std::io::Result<()> {
+        mbind(self.addr, self.size, nodes, policy, flags)
+    }
+
+    /// Gets the NUMA node of each page of the mapping (see `move_pages(2)`)
+    ///
+    /// There is one entry for each page (see `Map::page_size()`). Pages
+    /// which are not present yet are `None`.
+    pub fn node_of_pages(&self) -> std::io::Result<Vec<Option<u32>>> {
+        let step = self.page.bytes();
+        let pages: Vec<usize> = (self.addr..self.addr + self.size).step_by(step).collect();
+
+        let mut status: Vec<libc::c_int> = vec![0; pages.len()];
+        if pages.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_move_pages,
+                0,
+                pages.len() as libc::c_ulong,
+                pages.as_ptr(),
+                std::ptr::null::<libc::c_int>(),
+                status.as_mut_ptr(),
+                0,
+            )
+        };
+
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        // Negative values are errors, such as `-ENOENT` for missing pages.
+        Ok(status.into_iter().map(|s| u32::try_from(s).ok()).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page::PageSize;
+    use crate::{perms, Flags};
+    use std::io::ErrorKind;
+
+    /// Returns `true` if the system does not allow NUMA policies
+    fn unsupported(err: &std::io::Error) -> bool {
+        matches!(err.raw_os_error(), Some(libc::ENOSYS) | Some(libc::EPERM))
+    }
+
+    #[test]
+    fn bind() {
+        let psize = PageSize::base().bytes();
+        let mut map = Map::bytes(psize * 4)
+            .anywhere()
+            .anonymously()
+            .with(perms::ReadWrite)
+            .unwrap();
+
+        for policy in [Policy::Bind, Policy::Preferred, Policy::Interleave].iter() {
+            match map.bind(&[0], *policy, BindFlags::empty()) {
+                Err(e) if unsupported(&e) => return,
+                ret => ret.unwrap(),
+            }
+        }
+
+        map.bind(&[], Policy::Local, BindFlags::MOVE).unwrap();
+        let err = map.bind(&[0], Policy::Local, BindFlags::empty());
+        assert_eq!(err.unwrap_err().kind(), ErrorKind::InvalidInput);
+
+        map[0] = 1;
+        map[psize * 2] = 1;
+        let nodes = match map.node_of_pages() {
+            Err(e) if unsupported(&e) => return,
+            nodes => nodes.unwrap(),
+        };
+
+        assert_eq!(nodes, vec![Some(0), None, Some(0), None]);
+    }
+
+    #[test]
+    fn builder() {
+        let ret = Map::bytes(PageSize::base().bytes() * 2)
+            .anywhere()
+            .anonymously()
+            .with_flags(Flags::POPULATE)
+            .with_numa_policy(&[0], Policy::Bind)
+            .with(perms::ReadWrite);
+
+        let map = match ret {
+            Err(e) if unsupported(&e.err.into()) => return,
+            map => map.unwrap(),
+        };
+
+        match map.node_of_pages() {
+            Err(e) if unsupported(&e) => (),
+            nodes => assert_eq!(nodes.unwrap(), vec![Some(0); 2]),
+        }
+    }
+
+    #[test]
+    fn remap() {
+        let psize = PageSize::base().bytes();
+        let mut map = Map::bytes(psize)
+            .anywhere()
+            .anonymously()
+            .with(perms::ReadWrite)
+            .unwrap();
+
+        map[0] = 1;
+        let addr = map.addr();
+        let err = map
+            .remap()
+            .anonymously()
+            .with_numa_policy(&[9999], Policy::Bind)
+            .with(perms::ReadWrite)
+            .unwrap_err();
+        assert_eq!(err.err.raw_os_error(), Some(libc::EINVAL));
+
+        // The old mapping is still in place.
+        let map = err.map;
+        assert_eq!(map.addr(), addr);
+        assert_eq!(map[0], 1);
+    }
+}
@@ -0,0 +1,288 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Hooks for the lifecycle events of mappings
+//!
+//! An observer registered with `set_observer()` is notified when mappings
+//! are created (`Builder::with()`), remapped (`Map::remap()` and
+//! `Map::alias()`), reprotected (`Map::reprotect()`), split (`Map::split()`)
+//! and unmapped (when a `Map` is dropped), whether or not the operation
+//! succeeds. This can be used to feed the events into a tracing system:
+//!
+//! ```rust
+//! use mmarinus::observer::{self, Event, MapObserver};
+//! use mmarinus::{Map, perms};
+//!
+//! struct Logger;
+//!
+//! impl MapObserver for Logger {
+//!     fn on_map(&self, event: &Event<'_>) {
+//!         match event.err {
+//!             None => println!("mapped {} bytes at {:#x}", event.size, event.addr),
+//!             Some(err) => println!("failed to map {} bytes: {}", event.size, err),
+//!         }
+//!     }
+//! }
+//!
+//! observer::set_observer(&Logger).unwrap();
+//!
+//! let map = Map::bytes(4096)
+//!     .anywhere()
+//!     .anonymously()
+//!     .with(perms::Read)
+//!     .unwrap();
+//! ```
+//!
+//! Without an observer, the hooks cost a single atomic load. The observer
+//! is called synchronously, so it should be quick and should not create or
+//! drop mappings itself. It is never called for the mappings of the
+//! allocators in the `alloc` module, so it may allocate (see
+//! `Backend::HOOKS`).
+
+use super::backend::Backend;
+use super::errno::{self, Errno};
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// A lifecycle event of a mapping
+#[derive(Copy, Clone, Debug)]
+pub struct Event<'a> {
+    /// The address of the mapping
+    ///
+    /// If a mapping could not be created, this is the requested address
+    /// (which is zero for `Builder::anywhere()`).
+    pub addr: usize,
+
+    /// The size of the mapping
+    pub size: usize,
+
+    /// The permissions (`PROT_*`) of the mapping
+    ///
+    /// This is `None` if the permissions of the mapping are only known at
+    /// runtime (see `perms::Unknown`) and the event does not change them.
+    pub prot: Option<libc::c_int>,
+
+    /// The kind (`MAP_PRIVATE`, `MAP_SHARED`, ...) of the mapping
+    ///
+    /// This is `None` if the kind of the mapping is only known at runtime
+    /// (see `kinds::Unknown`) and the event does not change it.
+    pub kind: Option<libc::c_int>,
+
+    /// The error, if the operation failed
+    pub err: Option<&'a Errno>,
+}
+
+/// An observer of the lifecycle events of mappings
+///
+/// All methods do nothing by default, so observers only need to implement
+/// the events they are interested in.
+pub trait MapObserver: Sync {
+    /// Called when a mapping is created (see `Builder::with()`)
+    fn on_map(&self, _event: &Event<'_>) {}
+
+    /// Called when a mapping is unmapped (when a `Map` is dropped)
+    fn on_unmap(&self, _event: &Event<'_>) {}
+
+    /// Called when the permissions of a mapping are changed
+    ///
+    /// The event has the new permissions.
+    fn on_protect(&self, _event: &Event<'_>) {}
+
+    /// Called when a mapping is split at `offset`
+    fn on_split(&self, _event: &Event<'_>, _offset: usize) {}
+
+    /// Called when a mapping replaces existing pages
+    ///
+    /// This is the case for `Map::remap()` (and `Builder::onto()`) instead
+    /// of `MapObserver::on_map()`, and for `Map::alias()`.
+    fn on_remap(&self, _event: &Event<'_>) {}
+}
+
+const UNSET: u8 = 0;
+const SETTING: u8 = 1;
+const SET: u8 = 2;
+
+struct Global {
+    state: AtomicU8,
+    observer: UnsafeCell<Option<&'static dyn MapObserver>>,
+}
+
+// The observer is only written once, before the state becomes `SET`.
+unsafe impl Sync for Global {}
+
+static GLOBAL: Global = Global {
+    state: AtomicU8::new(UNSET),
+    observer: UnsafeCell::new(None),
+};
+
+/// Registers the observer for all mappings
+///
+/// The observer can only be registered once; later calls fail with `EBUSY`.
+pub fn set_observer(observer: &'static dyn MapObserver) -> errno::Result<()> {
+    let state = &GLOBAL.state;
+    if state
+        .compare_exchange(UNSET, SETTING, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        return Err(errno::from_raw(libc::EBUSY));
+    }
+
+    unsafe { *GLOBAL.observer.get() = Some(observer) };
+    GLOBAL.state.store(SET, Ordering::Release);
+    Ok(())
+}
+
+/// Gets the registered observer for mappings of the backend `B`, if any
+#[inline]
+pub(crate) fn get<B: Backend>() -> Option<&'static dyn MapObserver> {
+    if !B::HOOKS {
+        return None;
+    }
+
+    match GLOBAL.state.load(Ordering::Acquire) {
+        SET => unsafe { *GLOBAL.observer.get() },
+        _ => None,
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::page::PageSize;
+    use crate::{perms, Map};
+
+    use std::cell::RefCell;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Seen {
+        name: &'static str,
+        addr: usize,
+        size: usize,
+        prot: Option<libc::c_int>,
+        kind: Option<libc::c_int>,
+        err: Option<i32>,
+        offset: Option<usize>,
+    }
+
+    thread_local! {
+        // Other tests run concurrently, so only record this thread's events.
+        static SEEN: RefCell<Option<Vec<Seen>>> = RefCell::new(None);
+    }
+
+    struct Recorder;
+
+    impl Recorder {
+        fn record(&self, name: &'static str, event: &Event<'_>, offset: Option<usize>) {
+            let seen = Seen {
+                name,
+                addr: event.addr,
+                size: event.size,
+                prot: event.prot,
+                kind: event.kind,
+                err: event.err.and_then(|e| e.raw_os_error()),
+                offset,
+            };
+
+            let _ = SEEN.try_with(|s| s.borrow_mut().as_mut().map(|v| v.push(seen)));
+        }
+    }
+
+    impl MapObserver for Recorder {
+        fn on_map(&self, event: &Event<'_>) {
+            self.record("map", event, None)
+        }
+
+        fn on_unmap(&self, event: &Event<'_>) {
+            self.record("unmap", event, None)
+        }
+
+        fn on_protect(&self, event: &Event<'_>) {
+            self.record("protect", event, None)
+        }
+
+        fn on_split(&self, event: &Event<'_>, offset: usize) {
+            self.record("split", event, Some(offset))
+        }
+
+        fn on_remap(&self, event: &Event<'_>) {
+            self.record("remap", event, None)
+        }
+    }
+
+    #[test]
+    fn events() {
+        static RECORDER: Recorder = Recorder;
+
+        set_observer(&RECORDER).unwrap();
+        let err = set_observer(&RECORDER).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBUSY));
+
+        let psize = PageSize::base().bytes();
+        SEEN.with(|s| *s.borrow_mut() = Some(Vec::new()));
+
+        let map = Map::bytes(psize * 2)
+            .anywhere()
+            .anonymously()
+            .with(perms::Read)
+            .unwrap();
+
+        let addr = map.addr();
+        let map = map.reprotect(perms::ReadWrite).unwrap();
+        let map = map
+            .remap()
+            .anonymously()
+            .with(perms::Unknown(libc::PROT_READ))
+            .unwrap();
+
+        let (l, r) = map.split(psize).unwrap();
+        drop(l);
+        drop(r);
+
+        let err = Map::bytes(0)
+            .anywhere()
+            .anonymously()
+            .with(perms::Read)
+            .unwrap_err();
+        assert_eq!(err.err.raw_os_error(), Some(libc::EINVAL));
+
+        // The allocators do not notify the observer.
+        #[cfg(target_os = "linux")]
+        unsafe {
+            use crate::alloc::GuardAlloc;
+            use std::alloc::{GlobalAlloc, Layout};
+
+            let alloc = GuardAlloc::new(0);
+            let layout = Layout::from_size_align(16, 8).unwrap();
+            alloc.dealloc(alloc.alloc(layout), layout);
+        }
+
+        let seen = SEEN.with(|s| s.borrow_mut().take()).unwrap();
+        let event = |name, addr, size, prot, kind, err| Seen {
+            name,
+            addr,
+            size,
+            prot,
+            kind,
+            err,
+            offset: None,
+        };
+
+        let private = Some(libc::MAP_PRIVATE);
+        let read = Some(libc::PROT_READ);
+        let rw = Some(libc::PROT_READ | libc::PROT_WRITE);
+        assert_eq!(
+            seen,
+            vec![
+                event("map", addr, psize * 2, read, private, None),
+                event("protect", addr, psize * 2, rw, private, None),
+                event("remap", addr, psize * 2, read, private, None),
+                Seen {
+                    offset: Some(psize),
+                    ..event("split", addr, psize * 2, None, private, None)
+                },
+                event("unmap", addr, psize, None, private, None),
+                event("unmap", addr + psize, psize, None, private, None),
+                event("map", 0, 0, read, private, Some(libc::EINVAL)),
+            ]
+        );
+    }
+}
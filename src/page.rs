@@ -0,0 +1,355 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Page sizes supported by the system
+
+#[cfg(target_os = "linux")]
+use crate::errno;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(all(target_os = "linux", feature = "std"))]
+use std::io::ErrorKind;
+
+/// A page size in bytes
+///
+/// Page sizes are always powers of two.
+///
+/// ```rust
+/// use mmarinus::page::PageSize;
+///
+/// let base = PageSize::base();
+/// assert!(base.bytes().is_power_of_two());
+/// assert_eq!(1 << base.shift(), base.bytes());
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PageSize(usize);
+
+impl PageSize {
+    /// Gets the base page size of the system
+    ///
+    /// The value is queried from the system once and then cached.
+    #[inline]
+    pub fn base() -> Self {
+        static CACHE: AtomicUsize = AtomicUsize::new(0);
+
+        match CACHE.load(Ordering::Relaxed) {
+            0 => {
+                // `sysconf(_SC_PAGESIZE)` cannot fail on supported systems.
+                let size = match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+                    x if x > 0 => x as usize,
+                    _ => 4096,
+                };
+
+                CACHE.store(size, Ordering::Relaxed);
+                Self(size)
+            }
+
+            size => Self(size),
+        }
+    }
+
+    /// Creates a page size from a number of bytes
+    ///
+    /// Returns `None` if `bytes` is not a power of two.
+    #[inline]
+    pub fn new(bytes: usize) -> Option<Self> {
+        match bytes.is_power_of_two() {
+            true => Some(Self(bytes)),
+            false => None,
+        }
+    }
+
+    /// Gets the page size in bytes
+    #[inline]
+    pub fn bytes(self) -> usize {
+        self.0
+    }
+
+    /// Gets the base-2 logarithm of the page size
+    #[inline]
+    pub fn shift(self) -> u8 {
+        self.0.trailing_zeros() as u8
+    }
+
+    /// Returns `true` if `value` is a multiple of the page size
+    #[inline]
+    pub fn is_aligned(self, value: usize) -> bool {
+        value & (self.0 - 1) == 0
+    }
+
+    /// Rounds `value` up to a multiple of the page size
+    ///
+    /// Returns `None` on overflow.
+    #[inline]
+    pub fn round_up(self, value: usize) -> Option<usize> {
+        Some(value.checked_add(self.0 - 1)? & !(self.0 - 1))
+    }
+
+    /// Gets the default huge page size of the system
+    ///
+    /// This is the size used when `HugePageSize::Default` is requested and
+    /// is read from `Hugepagesize` in `/proc/meminfo`.
+    #[cfg(target_os = "linux")]
+    pub fn default_huge() -> errno::Result<Self> {
+        // This does not use `std::fs` so that it works without `std`.
+        let mut buf = [0u8; 16 * 1024];
+        let len = unsafe {
+            let path = b"/proc/meminfo\0";
+            let fd = libc::open(path.as_ptr() as _, libc::O_RDONLY | libc::O_CLOEXEC);
+            if fd < 0 {
+                return Err(errno::last());
+            }
+
+            let mut len = 0;
+            while len < buf.len() {
+                match libc::read(fd, buf[len..].as_mut_ptr() as _, buf.len() - len) {
+                    0 => break,
+                    n if n > 0 => len += n as usize,
+                    _ if errno::last().raw_os_error() == Some(libc::EINTR) => continue,
+                    _ => {
+                        let err = errno::last();
+                        libc::close(fd);
+                        return Err(err);
+                    }
+                }
+            }
+
+            libc::close(fd);
+            len
+        };
+
+        buf[..len]
+            .split(|b| *b == b'\n')
+            .filter_map(|line| line.strip_prefix(b"Hugepagesize:"))
+            .filter_map(|kb| {
+                kb.iter()
+                    .skip_while(|b| b.is_ascii_whitespace())
+                    .take_while(|b| b.is_ascii_digit())
+                    .try_fold(0usize, |n, b| {
+                        n.checked_mul(10)?.checked_add((b - b'0') as usize)
+                    })
+            })
+            .filter_map(|kb| kb.checked_mul(1024).and_then(Self::new))
+            .next()
+            .ok_or_else(|| errno::from_raw(libc::ENOENT))
+    }
+
+    /// Lists the huge page sizes supported by the system
+    ///
+    /// This is read from `/sys/kernel/mm/hugepages`. If the system does not
+    /// support huge pages, the list is empty. The list is sorted by size.
+    #[cfg(all(target_os = "linux", feature = "std"))]
+    pub fn huge() -> std::io::Result<Vec<HugePages>> {
+        let dir = match std::fs::read_dir("/sys/kernel/mm/hugepages") {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            dir => dir?,
+        };
+
+        let mut all = Vec::new();
+        for entry in dir {
+            let entry = entry?;
+            let name = entry.file_name();
+
+            let size = name
+                .to_str()
+                .and_then(|n| n.strip_prefix("hugepages-"))
+                .and_then(|n| n.strip_suffix("kB"))
+                .and_then(|n| n.parse::<usize>().ok())
+                .and_then(|kb| kb.checked_mul(1024))
+                .and_then(Self::new);
+
+            if let Some(size) = size {
+                all.push(HugePages::read(size, &entry.path())?);
+            }
+        }
+
+        all.sort_by_key(|h| h.size);
+        Ok(all)
+    }
+}
+
+/// The kind of pages backing a mapping
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Tier {
+    /// Huge pages reserved by the administrator (`MAP_HUGETLB` on Linux,
+    /// superpages on macOS)
+    Huge,
+
+    /// Base pages which the kernel may merge into transparent huge pages
+    /// (`MADV_HUGEPAGE`)
+    Transparent,
+
+    /// Base pages
+    Base,
+}
+
+/// The state of the pool of huge pages of one size
+///
+/// All counts are numbers of pages (see the kernel's `hugetlbpage.rst`).
+#[cfg(all(target_os = "linux", feature = "std"))]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HugePages {
+    /// The size of the huge pages
+    pub size: PageSize,
+
+    /// The number of persistent huge pages in the pool (`nr_hugepages`)
+    pub total: usize,
+
+    /// The number of huge pages not yet allocated (`free_hugepages`)
+    pub free: usize,
+
+    /// The number of free huge pages promised to existing mappings (`resv_hugepages`)
+    pub reserved: usize,
+
+    /// The number of huge pages allocated above the persistent pool (`surplus_hugepages`)
+    pub surplus: usize,
+
+    /// The maximum number of surplus huge pages (`nr_overcommit_hugepages`)
+    pub overcommit: usize,
+}
+
+#[cfg(all(target_os = "linux", feature = "std"))]
+impl HugePages {
+    fn read(size: PageSize, dir: &std::path::Path) -> std::io::Result<Self> {
+        let read = |name: &str| -> std::io::Result<usize> {
+            let data = std::fs::read_to_string(dir.join(name))?;
+            data.trim()
+                .parse()
+                .map_err(|_| ErrorKind::InvalidData.into())
+        };
+
+        Ok(Self {
+            size,
+            total: read("nr_hugepages")?,
+            free: read("free_hugepages")?,
+            reserved: read("resv_hugepages")?,
+            surplus: read("surplus_hugepages")?,
+            overcommit: read("nr_overcommit_hugepages")?,
+        })
+    }
+
+    /// Gets the number of huge pages that a new mapping can allocate
+    ///
+    /// This includes free pages not promised to other mappings as well as
+    /// the surplus pages which the kernel may still allocate.
+    #[inline]
+    pub fn available(&self) -> usize {
+        let free = self.free.saturating_sub(self.reserved);
+        free.saturating_add(self.overcommit.saturating_sub(self.surplus))
+    }
+}
+
+/// A huge page size for a mapping
+#[cfg(all(target_os = "linux", feature = "std"))]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HugePageSize {
+    /// The default huge page size of the system
+    Default,
+
+    /// 64 KiB huge pages
+    Size64K,
+
+    /// 2 MiB huge pages
+    Size2M,
+
+    /// 32 MiB huge pages
+    Size32M,
+
+    /// 1 GiB huge pages
+    Size1G,
+
+    /// Huge pages of the specified size
+    Other(PageSize),
+}
+
+#[cfg(all(target_os = "linux", feature = "std"))]
+impl HugePageSize {
+    /// Gets the page size, resolving `HugePageSize::Default`
+    pub fn size(self) -> std::io::Result<PageSize> {
+        Ok(PageSize(match self {
+            Self::Default => return Ok(PageSize::default_huge()?),
+            Self::Size64K => 64 << 10,
+            Self::Size2M => 2 << 20,
+            Self::Size32M => 32 << 20,
+            Self::Size1G => 1 << 30,
+            Self::Other(size) => return Ok(size),
+        }))
+    }
+
+    /// Checks that the system can back a mapping of `bytes` with these pages
+    ///
+    /// Fails with `ErrorKind::InvalidInput` if the system does not support
+    /// the page size and with `ErrorKind::OutOfMemory` if too few pages of
+    /// this size are available.
+    pub(crate) fn validate(self, bytes: usize) -> std::io::Result<PageSize> {
+        let size = self.size()?;
+
+        let pool = PageSize::huge()?.into_iter().find(|h| h.size == size);
+        let pool = match pool {
+            Some(pool) => pool,
+            None => {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("huge pages of {} KiB are not supported", size.0 >> 10),
+                ))
+            }
+        };
+
+        let needed = bytes / size.0 + (bytes % size.0 != 0) as usize;
+        if pool.total == 0 && pool.overcommit == 0 {
+            return Err(std::io::Error::new(
+                ErrorKind::OutOfMemory,
+                format!(
+                    "no huge pages of {} KiB are reserved; see /sys/kernel/mm/hugepages/hugepages-{}kB/nr_hugepages",
+                    size.0 >> 10,
+                    size.0 >> 10
+                ),
+            ));
+        }
+
+        if pool.available() < needed {
+            return Err(std::io::Error::new(
+                ErrorKind::OutOfMemory,
+                format!(
+                    "{} huge pages of {} KiB are needed but only {} are available",
+                    needed,
+                    size.0 >> 10,
+                    pool.available()
+                ),
+            ));
+        }
+
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base() {
+        let base = PageSize::base();
+        assert_eq!(base.bytes(), unsafe { libc::sysconf(libc::_SC_PAGESIZE) }
+            as usize);
+        assert_eq!(PageSize::base(), base);
+        assert!(base.is_aligned(base.bytes() * 3));
+        assert_eq!(base.round_up(1), Some(base.bytes()));
+        assert_eq!(base.round_up(usize::MAX), None);
+        assert_eq!(PageSize::new(3), None);
+    }
+
+    #[cfg(all(target_os = "linux", feature = "std"))]
+    #[test]
+    fn huge() {
+        let all = PageSize::huge().unwrap();
+        assert!(all.windows(2).all(|w| w[0].size < w[1].size));
+
+        if let Ok(default) = PageSize::default_huge() {
+            assert!(all.iter().any(|h| h.size == default));
+        }
+
+        let odd = PageSize::new(1 << 13).unwrap();
+        let err = HugePageSize::Other(odd).validate(1).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
@@ -31,6 +31,7 @@ perm! {
     ReadWriteExecute[Readable, Writeable, Executable] => libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
 }
 
+#[derive(Debug)]
 pub struct Unknown(pub libc::c_int);
 
 impl super::map::Type for Unknown {
@@ -0,0 +1,385 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use super::backend::Backend;
+use super::errno;
+use super::map::{Kind, Known};
+use super::Map;
+
+use core::ops::Range;
+use core::ptr::read_volatile;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+// Not exported by libc for all targets; see `include/uapi/asm-generic/mman-common.h`.
+#[cfg(target_os = "linux")]
+pub(crate) const MADV_POPULATE_READ: libc::c_int = 22;
+#[cfg(target_os = "linux")]
+pub(crate) const MADV_POPULATE_WRITE: libc::c_int = 23;
+
+/// The kind of access to prefault pages for
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Access {
+    /// Prefault the pages for reading
+    ///
+    /// For private and anonymous mappings, unwritten pages may map the
+    /// shared zero page, so the first write still faults.
+    Read,
+
+    /// Prefault the pages for writing
+    ///
+    /// This breaks copy-on-write, so private file mappings get private
+    /// copies of all of their pages.
+    Write,
+}
+
+impl<T: Known, K: Kind, B: Backend> Map<T, K, B> {
+    /// Faults in the pages of `range` (in bytes from the start of the mapping)
+    ///
+    /// This avoids the latency of page faults on first access, for example
+    /// for large files mapped with `Map::load()`. The range is extended to
+    /// page boundaries. Pages are populated with `MADV_POPULATE_READ` or
+    /// `MADV_POPULATE_WRITE` (Linux 5.14). Elsewhere, every page is touched
+    /// instead, without changing its contents. Touching a page for writing
+    /// is a write, though, so this is not done for `Private` mappings, whose
+    /// contents may be borrowed as slices; `Access::Write` then fails.
+    ///
+    /// Fails with `ErrorKind::InvalidInput` if the range is out of bounds,
+    /// if the permissions of the mapping do not allow the access or if the
+    /// pages of a `Private` mapping cannot be populated for writing.
+    ///
+    /// ```rust
+    /// use mmarinus::{Access, Map, perms};
+    ///
+    /// let map = Map::bytes(1 << 20)
+    ///     .anywhere()
+    ///     .anonymously()
+    ///     .with(perms::ReadWrite)
+    ///     .unwrap();
+    ///
+    /// map.prefault(0..map.size(), Access::Write).unwrap();
+    /// ```
+    ///
+    /// Note that touching pages of a file mapping past the end of the file
+    /// raises `SIGBUS` (see the `sigbus` module).
+    pub fn prefault(&self, range: Range<usize>, access: Access) -> errno::Result<()> {
+        let (addr, size) = self.pages(range, access)?;
+        unsafe { populate::<B>(addr, size, self.page.bytes(), access, Self::touch()) }
+    }
+
+    /// Like `Map::prefault()`, but spreads the work across `threads` threads
+    ///
+    /// This is useful for mappings of several GiB, where populating the
+    /// pages takes a noticeable time even without any I/O. The calling
+    /// thread is one of the threads, so `threads = 1` is equivalent to
+    /// `Map::prefault()`. At most one thread per online CPU is used.
+    ///
+    /// If a thread cannot be spawned, the threads which were spawned are
+    /// joined and the error is returned.
+    #[cfg(feature = "std")]
+    pub fn prefault_parallel(
+        &self,
+        range: Range<usize>,
+        access: Access,
+        threads: usize,
+    ) -> errno::Result<()>
+    where
+        B: 'static,
+    {
+        let (addr, size) = self.pages(range, access)?;
+        let touch = Self::touch();
+        let threads = threads.min(cpus()).max(1);
+        let page = self.page.bytes();
+        let pages = size / page;
+        let per = (pages + threads - 1) / threads;
+        let chunk = per.max(1) * page;
+
+        // The threads only capture the addresses, which stay valid since all
+        // threads are joined before returning.
+        let mut handles = Vec::new();
+        let mut ret = Ok(());
+        for start in (addr + chunk..addr + size).step_by(chunk) {
+            let len = chunk.min(addr + size - start);
+            let thread = std::thread::Builder::new()
+                .spawn(move || unsafe { populate::<B>(start, len, page, access, touch) });
+
+            match thread {
+                Ok(handle) => handles.push(handle),
+                Err(err) => {
+                    ret = Err(err.into());
+                    break;
+                }
+            }
+        }
+
+        if ret.is_ok() {
+            ret = unsafe { populate::<B>(addr, chunk.min(size), page, access, touch) };
+        }
+
+        let mut panic = None;
+        for handle in handles {
+            match handle.join() {
+                Ok(r) => ret = ret.and(r),
+                Err(p) => panic = panic.or(Some(p)),
+            }
+        }
+
+        if let Some(panic) = panic {
+            std::panic::resume_unwind(panic);
+        }
+
+        ret
+    }
+
+    /// Starts reading the pages of `range` (in bytes from the start of the
+    /// mapping) in the background
+    ///
+    /// This is `MADV_WILLNEED`, which, for file mappings, reads ahead the
+    /// part of the file which backs the range, like `posix_fadvise()` with
+    /// `POSIX_FADV_WILLNEED`. Unlike `Map::prefault()`, it does not wait for
+    /// the reads to complete and does not map the pages. The range is
+    /// extended to page boundaries.
+    pub fn readahead(&self, range: Range<usize>) -> errno::Result<()> {
+        let (addr, size) = self.span(range)?;
+        unsafe { B::madvise(addr, size, libc::MADV_WILLNEED) }
+    }
+
+    /// Indicates whether pages may be touched for writing
+    ///
+    /// This is not the case for `Private` mappings, which can be borrowed.
+    fn touch() -> bool {
+        K::KNOWN != Some(libc::MAP_PRIVATE)
+    }
+
+    /// Validates the range and the access, and extends it to page boundaries
+    fn pages(&self, range: Range<usize>, access: Access) -> errno::Result<(usize, usize)> {
+        let prot = match access {
+            Access::Read => libc::PROT_READ,
+            Access::Write => libc::PROT_WRITE,
+        };
+
+        if T::VALUE & prot == 0 {
+            return Err(errno::from_raw(libc::EINVAL));
+        }
+
+        self.span(range)
+    }
+
+    /// Validates the range and extends it to page boundaries
+    fn span(&self, range: Range<usize>) -> errno::Result<(usize, usize)> {
+        let einval = || errno::from_raw(libc::EINVAL);
+        if range.start > range.end || range.end > self.size {
+            return Err(einval());
+        }
+
+        let start = (self.addr + range.start) & !(self.page.bytes() - 1);
+        let end = match range.is_empty() {
+            true => start,
+            false => self
+                .page
+                .round_up(self.addr + range.end)
+                .ok_or_else(einval)?,
+        };
+
+        Ok((start, end - start))
+    }
+}
+
+/// Gets the number of online CPUs
+#[cfg(feature = "std")]
+fn cpus() -> usize {
+    match unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) } {
+        n if n > 0 => n as usize,
+        _ => 1,
+    }
+}
+
+/// Faults in the pages at `addr`
+///
+/// Without `MADV_POPULATE_*`, the pages are touched, but only read unless
+/// `touch` allows writes.
+///
+/// # Safety
+///
+/// The pages must be mapped with permissions allowing the access.
+unsafe fn populate<B: Backend>(
+    addr: usize,
+    size: usize,
+    page: usize,
+    access: Access,
+    touch: bool,
+) -> errno::Result<()> {
+    if size == 0 {
+        return Ok(());
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        let advice = match access {
+            Access::Read => MADV_POPULATE_READ,
+            Access::Write => MADV_POPULATE_WRITE,
+        };
+
+        match B::madvise(addr, size, advice) {
+            // Linux before 5.14.
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => (),
+            ret => return ret,
+        }
+    }
+
+    if access == Access::Write && !touch {
+        return Err(errno::from_raw(libc::EINVAL));
+    }
+
+    for addr in (addr..addr + size).step_by(page) {
+        match access {
+            Access::Read => {
+                read_volatile(addr as *const u8);
+            }
+
+            // An atomic no-op, so that concurrent writes are not lost.
+            Access::Write => {
+                (*(addr as *const AtomicU8)).fetch_add(0, Ordering::Relaxed);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::backend::{Mock, Syscall};
+    use crate::page::PageSize;
+    use crate::{perms, Shared};
+
+    /// Counts the resident pages of the mapping
+    fn resident<T: Known, K: Kind, B: Backend>(map: &Map<T, K, B>) -> usize {
+        let psize = PageSize::base().bytes();
+        let mut vec = vec![0u8; (map.size() + psize - 1) / psize];
+        let ret = unsafe { libc::mincore(map.addr() as *mut _, map.size(), vec.as_mut_ptr()) };
+        assert_eq!(ret, 0);
+        vec.iter().filter(|v| *v & 1 != 0).count()
+    }
+
+    #[test]
+    fn prefault() {
+        let psize = PageSize::base().bytes();
+        let map = Map::bytes(psize * 16)
+            .anywhere()
+            .anonymously()
+            .with(perms::ReadWrite)
+            .unwrap();
+
+        assert_eq!(resident(&map), 0);
+        map.prefault(psize + 1..psize * 3 + 1, Access::Write)
+            .unwrap();
+        assert_eq!(resident(&map), 3);
+
+        map.prefault(0..0, Access::Read).unwrap();
+        assert!(map.prefault(0..psize * 17, Access::Read).is_err());
+
+        let map = Map::bytes(psize)
+            .anywhere()
+            .anonymously()
+            .with(perms::Read)
+            .unwrap();
+
+        let err = map.prefault(0..psize, Access::Write).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[test]
+    fn fallback() {
+        let psize = PageSize::base().bytes();
+        let mut map = Map::bytes(psize * 4)
+            .anywhere()
+            .anonymously()
+            .with_kind(Shared)
+            .with_backend::<Mock>()
+            .with(perms::ReadWrite)
+            .unwrap();
+
+        map.copy_from(psize, &[7]).unwrap();
+        Mock::reset();
+
+        // Pretend that MADV_POPULATE_WRITE is not supported.
+        Mock::fail(Syscall::Madvise, libc::EINVAL);
+        map.prefault(0..map.size(), Access::Write).unwrap();
+        assert_eq!(resident(&map), 4);
+        let mut buf = [0u8];
+        map.copy_to(psize, &mut buf).unwrap();
+        assert_eq!(buf, [7]);
+        assert_eq!(Mock::calls().len(), 1);
+
+        Mock::fail(Syscall::Madvise, libc::EFAULT);
+        let err = map.prefault(0..1, Access::Read).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EFAULT));
+
+        // Private mappings may be borrowed, so they are not written to.
+        let map = Map::bytes(psize * 4)
+            .anywhere()
+            .anonymously()
+            .with_backend::<Mock>()
+            .with(perms::ReadWrite)
+            .unwrap();
+
+        Mock::fail(Syscall::Madvise, libc::EINVAL);
+        let err = map.prefault(0..map.size(), Access::Write).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        assert_eq!(resident(&map), 0);
+
+        Mock::fail(Syscall::Madvise, libc::EINVAL);
+        map.prefault(0..map.size(), Access::Read).unwrap();
+        Mock::reset();
+    }
+
+    #[test]
+    fn parallel() {
+        let psize = PageSize::base().bytes();
+        let map = Map::bytes(psize * 37)
+            .anywhere()
+            .anonymously()
+            .with(perms::ReadWrite)
+            .unwrap();
+
+        map.prefault_parallel(0..map.size(), Access::Write, 4)
+            .unwrap();
+        assert_eq!(resident(&map), 37);
+
+        map.prefault_parallel(0..psize, Access::Read, 0).unwrap();
+        map.prefault_parallel(0..0, Access::Read, 8).unwrap();
+    }
+
+    #[test]
+    fn readahead() {
+        let path = std::env::temp_dir().join(format!("mmarinus-readahead-{}", std::process::id()));
+        std::fs::write(&path, [1u8; 16]).unwrap();
+        let mut file = std::fs::File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let map = Map::bytes(16)
+            .anywhere()
+            .from(&mut file, 0)
+            .with(perms::Read)
+            .unwrap();
+
+        map.readahead(0..16).unwrap();
+        map.prefault(0..16, Access::Read).unwrap();
+        assert_eq!(resident(&map), 1);
+        assert!(map.readahead(Range { start: 8, end: 4 }).is_err());
+
+        Mock::reset();
+        let map = Map::bytes(16)
+            .anywhere()
+            .from(&mut file, 0)
+            .with_backend::<Mock>()
+            .with(perms::Read)
+            .unwrap();
+
+        map.readahead(0..16).unwrap();
+        let call = *Mock::calls().last().unwrap();
+        assert_eq!(call.syscall, Syscall::Madvise);
+        assert_eq!((call.addr, call.arg), (map.addr(), libc::MADV_WILLNEED));
+        Mock::reset();
+    }
+}
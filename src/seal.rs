@@ -0,0 +1,179 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use super::errno::Errno;
+use super::error::{Op, Operation};
+use super::map::{Kind, Readable, Safe, Type, Writeable};
+use super::page::PageSize;
+use super::{Error, Map, Private};
+
+use std::io::ErrorKind;
+use std::mem::ManuallyDrop;
+
+// Not exported by libc for all targets; see `include/uapi/asm-generic/unistd.h`.
+const SYS_MSEAL: libc::c_long = 462;
+
+/// A mapping which can no longer be changed
+///
+/// A sealed mapping (see `mseal(2)`) cannot be unmapped, remapped or
+/// reprotected for the lifetime of the process. Accordingly, this type
+/// provides the same access to the data as `Map` but none of the functions
+/// which would change the mapping. Dropping it does not unmap it.
+///
+/// ```rust
+/// use mmarinus::{Map, perms};
+/// use std::io::ErrorKind;
+///
+/// let mut map = Map::bytes(4096)
+///     .anywhere()
+///     .anonymously()
+///     .with(perms::ReadWrite)
+///     .unwrap();
+///
+/// map[0] = 1;
+///
+/// match map.seal() {
+///     Ok(sealed) => assert_eq!(sealed[0], 1),
+///
+///     // Linux older than 6.10
+///     Err(e) => assert_eq!(e.err.kind(), ErrorKind::Unsupported),
+/// }
+/// ```
+#[derive(Debug)]
+pub struct SealedMap<T: Type, K: Kind = Private>(ManuallyDrop<Map<T, K>>);
+
+impl<T: Type, K: Kind> Map<T, K> {
+    /// Seals the mapping against further changes
+    ///
+    /// On kernels without `mseal()` (before Linux 6.10 and on 32-bit
+    /// systems), this fails with `ErrorKind::Unsupported` and the mapping
+    /// is returned unchanged.
+    pub fn seal(self) -> Result<SealedMap<T, K>, Error<Self>> {
+        if unsafe { libc::syscall(SYS_MSEAL, self.addr, self.size, 0) } != 0 {
+            let err = match std::io::Error::last_os_error() {
+                e if e.raw_os_error() == Some(libc::ENOSYS) => std::io::Error::new(
+                    ErrorKind::Unsupported,
+                    "mseal() is not supported by this kernel",
+                ),
+                e => e,
+            };
+
+            let op = Operation {
+                op: Op::Seal,
+                addr: self.addr,
+                size: self.size,
+                prot: 0,
+                flags: 0,
+            };
+
+            return Err(Error {
+                map: self,
+                err: Errno::from(err).with(op),
+            });
+        }
+
+        Ok(SealedMap(ManuallyDrop::new(self)))
+    }
+}
+
+impl<T: Type, K: Kind> SealedMap<T, K> {
+    /// Gets the address of the mapping
+    #[inline]
+    pub fn addr(&self) -> usize {
+        self.0.addr()
+    }
+
+    /// Gets the size of the mapping
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.0.size()
+    }
+
+    /// Gets the size of the pages backing the mapping
+    #[inline]
+    pub fn page_size(&self) -> PageSize {
+        self.0.page_size()
+    }
+}
+
+impl<T: Readable, K: Kind> SealedMap<T, K> {
+    /// Copies bytes out of the mapping (see `Map::copy_to()`)
+    #[inline]
+    pub fn copy_to(&self, offset: usize, buf: &mut [u8]) -> std::io::Result<()> {
+        Ok(self.0.copy_to(offset, buf)?)
+    }
+}
+
+impl<T: Writeable, K: Kind> SealedMap<T, K> {
+    /// Copies bytes into the mapping (see `Map::copy_from()`)
+    #[inline]
+    pub fn copy_from(&mut self, offset: usize, buf: &[u8]) -> std::io::Result<()> {
+        Ok(self.0.copy_from(offset, buf)?)
+    }
+}
+
+impl<K: Safe, T: Readable> std::ops::Deref for SealedMap<T, K> {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl<K: Safe, T: Readable + Writeable> std::ops::DerefMut for SealedMap<T, K> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+impl<K: Safe, T: Readable> AsRef<[u8]> for SealedMap<T, K> {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl<K: Safe, T: Readable + Writeable> AsMut<[u8]> for SealedMap<T, K> {
+    #[inline]
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut *self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{perms, Shared};
+
+    #[test]
+    fn seal() {
+        let psize = PageSize::base().bytes();
+        let mut map = Map::bytes(psize * 2)
+            .anywhere()
+            .anonymously()
+            .with_kind(Shared)
+            .with(perms::ReadWrite)
+            .unwrap();
+
+        map.copy_from(0, b"sealed").unwrap();
+
+        let mut sealed = match map.seal() {
+            Err(e) => return assert_eq!(e.err.kind(), ErrorKind::Unsupported),
+            Ok(sealed) => sealed,
+        };
+
+        sealed.copy_from(psize, b"still writable").unwrap();
+        let mut buf = [0u8; 6];
+        sealed.copy_to(0, &mut buf).unwrap();
+        assert_eq!(&buf, b"sealed");
+
+        let addr = sealed.addr() as *mut libc::c_void;
+        assert_ne!(unsafe { libc::munmap(addr, psize) }, 0);
+        assert_ne!(unsafe { libc::mprotect(addr, psize, libc::PROT_READ) }, 0);
+        assert_eq!(
+            std::io::Error::last_os_error().raw_os_error(),
+            Some(libc::EPERM)
+        );
+    }
+}
@@ -0,0 +1,317 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use super::error::{Op, Operation};
+use super::page::PageSize;
+use super::{errno, kinds, perms, Error, Map, Private, Reason, Shared};
+
+use core::mem::size_of;
+use core::ptr::write_volatile;
+use core::slice::{from_raw_parts, from_raw_parts_mut};
+use core::sync::atomic::{compiler_fence, Ordering};
+
+// Not exported by libc for all targets; see `include/uapi/asm-generic/unistd.h`.
+const SYS_MEMFD_SECRET: libc::c_long = 447;
+
+/// A mapping for secrets, such as key material
+///
+/// The memory of a secret mapping is:
+///
+///   * locked into memory (see `mlock(2)`), so it is never swapped out,
+///   * excluded from core dumps (`MADV_DONTDUMP`),
+///   * not inherited by child processes (`MADV_WIPEONFORK` or
+///     `MADV_DONTFORK`),
+///   * surrounded by `PROT_NONE` guard pages, so that overflows fault, and
+///   * overwritten with zeros before it is unmapped.
+///
+/// Where the kernel supports it, the memory is allocated with
+/// `memfd_secret(2)`, which also removes it from the kernel's direct map.
+/// Otherwise, it is a private anonymous mapping. Since the kind of the
+/// mapping is only known at runtime, it is stored as a
+/// `Map<perms::ReadWrite, kinds::Unknown>`: a `Shared` mapping of the
+/// `memfd_secret()` file or a `Private` anonymous one (see `is_secretmem()`).
+///
+/// ```rust
+/// use mmarinus::SecretMap;
+///
+/// let mut key = SecretMap::new(32).unwrap();
+/// key[..4].copy_from_slice(b"key!");
+/// assert_eq!(&key[..4], b"key!");
+/// ```
+#[derive(Debug)]
+pub struct SecretMap {
+    lower: Map<perms::None>,
+    map: Map<perms::ReadWrite, kinds::Unknown>,
+    upper: Map<perms::None>,
+    secretmem: bool,
+}
+
+impl SecretMap {
+    /// Creates a secret mapping of (at least) `size` bytes
+    ///
+    /// The size is rounded up to a multiple of the page size. Fails with
+    /// `Reason::MemlockLimit` if the memory cannot be locked, whether it is
+    /// allocated with `memfd_secret()` or not.
+    #[inline]
+    pub fn new(size: usize) -> Result<Self, Error<()>> {
+        Self::build(size, true)
+    }
+
+    fn build(size: usize, secretmem: bool) -> Result<Self, Error<()>> {
+        let page = PageSize::base();
+        let einval = || Error::new((), errno::from_raw(libc::EINVAL));
+        let size = match page.round_up(size) {
+            Some(size) if size > 0 => size,
+            _ => return Err(einval()),
+        };
+
+        let total = size.checked_add(page.bytes() * 2).ok_or_else(einval)?;
+        let reserved = Map::bytes(total)
+            .anywhere()
+            .anonymously()
+            .with(perms::None)?;
+
+        let (lower, rest) = reserved.split(page.bytes()).map_err(|e| e.err)?;
+        let (middle, upper) = rest.split(size).map_err(|e| e.err)?;
+
+        let middle = match secretmem {
+            true => Self::secretmem(middle),
+            false => Err(Error::new(middle, errno::from_raw(libc::ENOSYS))),
+        };
+
+        let (map, secretmem) = match middle {
+            Ok(map) => (map, true),
+            Err(e) if e.reason() == Reason::MemlockLimit => return Err(e.map_inner(|_| ())),
+            Err(Error { map: middle, .. }) => {
+                let map = middle
+                    .remap()
+                    .anonymously()
+                    .with_kind(Private)
+                    .with(perms::ReadWrite)
+                    .map_err(|e| e.err)?;
+
+                (map.into(), false)
+            }
+        };
+
+        let ret = Self {
+            lower,
+            map,
+            upper,
+            secretmem,
+        };
+
+        ret.protect()?;
+        Ok(ret)
+    }
+
+    /// Maps the reserved range with `memfd_secret()`, if possible
+    ///
+    /// On failure, the reserved range is returned unchanged. The kernel
+    /// locks the memory when it is mapped, so exceeding `RLIMIT_MEMLOCK` is
+    /// reported as a failure of `Op::Lock`.
+    fn secretmem(
+        reserved: Map<perms::None>,
+    ) -> Result<Map<perms::ReadWrite, kinds::Unknown>, Error<Map<perms::None>>> {
+        // Fails if unsupported by the kernel or not enabled (`secretmem.enable`).
+        let fd = unsafe { libc::syscall(SYS_MEMFD_SECRET, libc::O_CLOEXEC) } as libc::c_int;
+        if fd < 0 {
+            return Err(Error::new(reserved, errno::last()));
+        }
+
+        let (addr, size) = (reserved.addr(), reserved.size());
+        let ret = match unsafe { libc::ftruncate(fd, size as libc::off_t) } {
+            0 => reserved
+                .remap()
+                .fd(fd, 0)
+                .with_kind(Shared)
+                .with(perms::ReadWrite)
+                .map(Into::into)
+                .map_err(|e| match e.err.raw_os_error() {
+                    Some(libc::EAGAIN) => Error {
+                        err: e.err.with(Operation {
+                            op: Op::Lock,
+                            addr,
+                            size,
+                            prot: libc::PROT_READ | libc::PROT_WRITE,
+                            flags: libc::MAP_SHARED,
+                        }),
+                        map: e.map,
+                    },
+                    _ => e,
+                }),
+            _ => Err(Error::new(reserved, errno::last())),
+        };
+
+        unsafe { libc::close(fd) };
+        ret
+    }
+
+    /// Locks the memory and excludes it from core dumps and children
+    fn protect(&self) -> Result<(), Error<()>> {
+        let (addr, size) = (self.map.addr(), self.map.size());
+        let advise = |advice| match unsafe { libc::madvise(addr as *mut _, size, advice) } {
+            0 => Ok(()),
+            _ => Err(Error::new((), errno::last())),
+        };
+
+        // The kernel always locks `memfd_secret()` memory and excludes it
+        // from core dumps.
+        if self.secretmem {
+            return advise(libc::MADV_DONTFORK);
+        }
+
+        if unsafe { libc::mlock(addr as *const _, size) } != 0 {
+            return Err(Error {
+                map: (),
+                err: errno::last().with(Operation {
+                    op: Op::Lock,
+                    addr,
+                    size,
+                    prot: libc::PROT_READ | libc::PROT_WRITE,
+                    flags: 0,
+                }),
+            });
+        }
+
+        advise(libc::MADV_DONTDUMP)?;
+
+        // MADV_WIPEONFORK was added in Linux 4.14.
+        advise(libc::MADV_WIPEONFORK).or_else(|_| advise(libc::MADV_DONTFORK))
+    }
+
+    /// Gets the address of the memory
+    #[inline]
+    pub fn addr(&self) -> usize {
+        self.map.addr()
+    }
+
+    /// Gets the size of the memory
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.map.size()
+    }
+
+    /// Gets the addresses of the guard pages below and above the memory
+    #[inline]
+    pub fn guards(&self) -> (usize, usize) {
+        (self.lower.addr(), self.upper.addr())
+    }
+
+    /// Indicates whether the memory was allocated with `memfd_secret()`
+    #[inline]
+    pub fn is_secretmem(&self) -> bool {
+        self.secretmem
+    }
+
+    /// Overwrites the memory with zeros
+    ///
+    /// This uses volatile writes, so it is not optimized away. It is called
+    /// automatically when the mapping is dropped.
+    pub fn wipe(&mut self) {
+        const WORD: usize = size_of::<usize>();
+
+        let ptr = self.map.addr() as *mut usize;
+        for i in 0..self.map.size() / WORD {
+            unsafe { write_volatile(ptr.add(i), 0) };
+        }
+
+        compiler_fence(Ordering::SeqCst);
+    }
+}
+
+impl Drop for SecretMap {
+    fn drop(&mut self) {
+        // The guard pages and the memory are unmapped afterwards.
+        self.wipe();
+    }
+}
+
+impl core::ops::Deref for SecretMap {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        // The memory is only mapped here: the `memfd_secret()` file
+        // descriptor is closed and children do not inherit the mapping.
+        unsafe { from_raw_parts(self.map.addr() as *const u8, self.map.size()) }
+    }
+}
+
+impl core::ops::DerefMut for SecretMap {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { from_raw_parts_mut(self.map.addr() as *mut u8, self.map.size()) }
+    }
+}
+
+impl AsRef<[u8]> for SecretMap {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl AsMut<[u8]> for SecretMap {
+    #[inline]
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut *self
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    /// Gets the `VmFlags` of the mapping at `addr` from `/proc/self/smaps`
+    fn flags(addr: usize) -> Vec<String> {
+        let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap();
+        let start = format!("{:x}-", addr);
+        smaps
+            .split('\n')
+            .skip_while(|line| !line.starts_with(&start))
+            .find_map(|line| line.strip_prefix("VmFlags:"))
+            .unwrap()
+            .split_whitespace()
+            .map(String::from)
+            .collect()
+    }
+
+    fn check(mut map: SecretMap) {
+        let psize = PageSize::base().bytes();
+        assert_eq!(map.size(), psize);
+        let (lower, upper) = map.guards();
+        assert_eq!(lower + psize, map.addr());
+        assert_eq!(map.addr() + psize, upper);
+
+        map[..6].copy_from_slice(b"secret");
+        assert_eq!(&map[..6], b"secret");
+
+        let flags = flags(map.addr());
+        assert!(flags.iter().any(|f| f == "lo"), "{:?}", flags);
+        assert!(flags.iter().any(|f| f == "dd"), "{:?}", flags);
+
+        map.wipe();
+        assert!(map.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn secret() {
+        check(SecretMap::new(100).unwrap());
+    }
+
+    #[test]
+    fn fallback() {
+        let map = SecretMap::build(1, false).unwrap();
+        assert!(!map.is_secretmem());
+
+        let flags = flags(map.addr());
+        assert!(flags.iter().any(|f| f == "wf"), "{:?}", flags);
+        check(map);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(SecretMap::new(0).is_err());
+        assert!(SecretMap::new(usize::MAX).is_err());
+    }
+}
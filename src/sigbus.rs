@@ -0,0 +1,467 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Recovery from `SIGBUS` in file-backed mappings
+//!
+//! Accessing a page of a file-backed mapping which lies beyond the end of
+//! the file raises `SIGBUS`. Normally, this kills the process. This can
+//! happen at any time if another process truncates the file.
+//!
+//! The fallible accessors on `Map` (i.e. `Map::try_copy_to()` and
+//! `Map::try_copy_from()`) install a process-wide `SIGBUS` handler. When a
+//! fault occurs inside the mapping during one of these calls, the handler
+//! moves the pages from the faulting one to the end of the mapping aside
+//! (with `mremap()`) and maps zeroed anonymous pages in their place. The
+//! copy then continues: it reads zeros and its writes to these pages are
+//! discarded. Afterwards, the pages of the file are moved back and the copy
+//! fails with `ErrorKind::UnexpectedEof`. The mapping itself is left intact,
+//! so it becomes accessible again if the file is extended. All other
+//! `SIGBUS` signals are passed to the previously installed handler.
+//!
+//! Where the filesystem supports it, a `Lease` can be used to be notified
+//! before another process opens the file for writing or truncates it.
+
+use std::fs::File;
+use std::io::ErrorKind;
+use std::mem::{zeroed, MaybeUninit};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Once;
+
+/// The most pages which can be moved aside during one access
+const MOVES: usize = 8;
+
+/// Pages of the file which were moved aside by `on_sigbus()`
+#[derive(Copy, Clone)]
+struct Moved {
+    addr: usize,
+    size: usize,
+    aside: usize,
+}
+
+/// The range of the access currently being guarded on this thread
+#[derive(Copy, Clone)]
+struct Active {
+    start: usize,
+    end: usize,
+    page: usize,
+    moved: [Option<Moved>; MOVES],
+}
+
+impl Active {
+    /// Gets the address up to which pages can still be moved aside
+    fn limit(&self) -> usize {
+        let moved = self.moved.iter().flatten().map(|m| m.addr);
+        moved.min().unwrap_or(self.end)
+    }
+}
+
+thread_local! {
+    static ACTIVE: std::cell::Cell<Option<Active>> = std::cell::Cell::new(None);
+}
+
+/// The handlers which were installed before ours
+struct Previous(std::cell::UnsafeCell<MaybeUninit<libc::sigaction>>);
+
+// Only written once, before our handler is installed.
+unsafe impl Sync for Previous {}
+
+static SIGBUS: Previous = Previous(std::cell::UnsafeCell::new(MaybeUninit::uninit()));
+static SIGIO: Previous = Previous(std::cell::UnsafeCell::new(MaybeUninit::uninit()));
+
+/// Installs `handler` for `signal`, saving the old handler in `prev`
+unsafe fn install(
+    signal: libc::c_int,
+    prev: &Previous,
+    handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void),
+) -> std::io::Result<()> {
+    let mut new: libc::sigaction = zeroed();
+    new.sa_sigaction = handler as usize;
+    new.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK | libc::SA_RESTART;
+    libc::sigemptyset(&mut new.sa_mask);
+
+    if libc::sigaction(signal, &new, (*prev.0.get()).as_mut_ptr()) != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Passes a signal that we do not handle to the previous handler
+unsafe fn chain(
+    signal: libc::c_int,
+    prev: &Previous,
+    info: *mut libc::siginfo_t,
+    ctx: *mut libc::c_void,
+) {
+    let prev = (*prev.0.get()).as_ptr();
+
+    match (*prev).sa_sigaction {
+        libc::SIG_IGN => (),
+
+        // Restore the default action and deliver the signal again. Since
+        // the signal is blocked while we run, it will be delivered when we
+        // return.
+        libc::SIG_DFL => {
+            libc::sigaction(signal, prev, null_mut());
+            libc::raise(signal);
+        }
+
+        f if (*prev).sa_flags & libc::SA_SIGINFO != 0 => {
+            let f: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+                std::mem::transmute(f);
+            f(signal, info, ctx)
+        }
+
+        f => {
+            let f: extern "C" fn(libc::c_int) = std::mem::transmute(f);
+            f(signal)
+        }
+    }
+}
+
+/// Moves the pages from `addr` up to `limit` aside and maps zeroes instead
+///
+/// This only uses system calls, which are safe to use in signal handlers.
+unsafe fn replace(addr: usize, limit: usize) -> Option<Moved> {
+    let size = limit - addr;
+    let none = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE;
+    let aside = libc::mmap(null_mut(), size, libc::PROT_NONE, none, -1, 0);
+    if aside == libc::MAP_FAILED {
+        return None;
+    }
+
+    let flags = libc::MREMAP_MAYMOVE | libc::MREMAP_FIXED;
+    if libc::mremap(addr as _, size, size, flags, aside) == libc::MAP_FAILED {
+        libc::munmap(aside, size);
+        return None;
+    }
+
+    let prot = libc::PROT_READ | libc::PROT_WRITE;
+    let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED;
+    if libc::mmap(addr as _, size, prot, flags, -1, 0) == libc::MAP_FAILED {
+        let flags = libc::MREMAP_MAYMOVE | libc::MREMAP_FIXED;
+        libc::mremap(aside, size, size, flags, addr);
+        return None;
+    }
+
+    Some(Moved {
+        addr,
+        size,
+        aside: aside as usize,
+    })
+}
+
+extern "C" fn on_sigbus(signal: libc::c_int, info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+    let addr = unsafe { (*info).si_addr() } as usize;
+
+    let handled = ACTIVE.with(|active| {
+        let mut a = match active.get() {
+            Some(a) if addr >= a.start && addr < a.limit() => a,
+            _ => return false,
+        };
+
+        let slot = match a.moved.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None => return false,
+        };
+
+        // Returning retries the access, which then hits the zeroed pages.
+        let page = addr & !(a.page - 1);
+        a.moved[slot] = unsafe { replace(page, a.limit()) };
+        active.set(Some(a));
+        a.moved[slot].is_some()
+    });
+
+    if !handled {
+        unsafe { chain(signal, &SIGBUS, info, ctx) }
+    }
+}
+
+/// Runs `f` with `SIGBUS` faults in `[addr, addr + size)` turned into errors
+///
+/// The mapping must consist of pages of `page` bytes.
+pub(crate) fn guard<F: FnOnce()>(
+    addr: usize,
+    size: usize,
+    page: usize,
+    f: F,
+) -> std::io::Result<()> {
+    static ONCE: Once = Once::new();
+    static INSTALLED: AtomicI32 = AtomicI32::new(0);
+
+    ONCE.call_once(|| {
+        if let Err(e) = unsafe { install(libc::SIGBUS, &SIGBUS, on_sigbus) } {
+            let errno = e.raw_os_error().unwrap_or(libc::EINVAL);
+            INSTALLED.store(errno, Ordering::Relaxed);
+        }
+    });
+
+    match INSTALLED.load(Ordering::Relaxed) {
+        0 => (),
+        errno => return Err(std::io::Error::from_raw_os_error(errno)),
+    }
+
+    let next = Active {
+        start: addr,
+        end: (addr + size + page - 1) & !(page - 1),
+        page,
+        moved: [None; MOVES],
+    };
+
+    let prev = ACTIVE.with(|a| a.replace(Some(next)));
+    f();
+    let moved = ACTIVE
+        .with(|a| a.replace(prev))
+        .map_or([None; MOVES], |a| a.moved);
+
+    // Put the pages of the file back in place of the zeroed pages.
+    for m in moved.iter().flatten() {
+        let flags = libc::MREMAP_MAYMOVE | libc::MREMAP_FIXED;
+        let ret = unsafe { libc::mremap(m.aside as _, m.size, m.size, flags, m.addr) };
+        if ret == libc::MAP_FAILED {
+            unsafe { libc::munmap(m.aside as _, m.size) };
+        }
+    }
+
+    match moved.iter().any(Option::is_some) {
+        false => Ok(()),
+        true => Err(std::io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "SIGBUS while accessing the mapping; the file may have been truncated",
+        )),
+    }
+}
+
+const LEASES: usize = 64;
+
+// Not exported by libc for all targets; see `include/uapi/asm-generic/fcntl.h`.
+const F_SETSIG: libc::c_int = 10;
+
+/// The file descriptors of all active leases
+static FDS: [AtomicI32; LEASES] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: AtomicI32 = AtomicI32::new(-1);
+    [EMPTY; LEASES]
+};
+
+/// The layout of `siginfo_t` for `SIGIO`/`SIGPOLL`
+#[repr(C)]
+struct SigPoll {
+    signo: libc::c_int,
+    errno: libc::c_int,
+    code: libc::c_int,
+    band: libc::c_long,
+    fd: libc::c_int,
+}
+
+extern "C" fn on_sigio(signal: libc::c_int, info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+    let fd = unsafe { (*(info as *const SigPoll)).fd };
+
+    if fd < 0 || !FDS.iter().any(|x| x.load(Ordering::Relaxed) == fd) {
+        unsafe { chain(signal, &SIGIO, info, ctx) }
+    }
+}
+
+/// A read lease on a file
+///
+/// A lease (see `F_SETLEASE` in `fcntl(2)`) allows a process to be notified
+/// when another process opens a file for writing or truncates it. The
+/// other process is blocked until the lease is released (by dropping this
+/// value) or until `/proc/sys/fs/lease-break-time` seconds have elapsed.
+///
+/// Users of a mapping protected by a lease should check `Lease::breaking()`
+/// regularly and, when it returns `true`, stop using the mapping and drop
+/// the lease.
+///
+/// Only files owned by the current user can be leased and the file must
+/// be opened read-only. Not all filesystems support leases.
+///
+/// ```rust,no_run
+/// use mmarinus::{sigbus::Lease, Map, Private, perms};
+///
+/// let file = std::fs::File::open("/var/lib/data/table").unwrap();
+/// let lease = Lease::new(&file).unwrap();
+/// let map = Map::load("/var/lib/data/table", Private, perms::Read).unwrap();
+///
+/// let mut buf = [0u8; 64];
+/// while !lease.breaking() {
+///     map.try_copy_to(0, &mut buf).unwrap();
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Lease {
+    fd: RawFd,
+    slot: usize,
+}
+
+impl Lease {
+    /// Takes a read lease on `file`
+    pub fn new(file: &File) -> std::io::Result<Self> {
+        static ONCE: Once = Once::new();
+        static INSTALLED: AtomicI32 = AtomicI32::new(0);
+
+        ONCE.call_once(|| {
+            if let Err(e) = unsafe { install(libc::SIGIO, &SIGIO, on_sigio) } {
+                let errno = e.raw_os_error().unwrap_or(libc::EINVAL);
+                INSTALLED.store(errno, Ordering::Relaxed);
+            }
+        });
+
+        match INSTALLED.load(Ordering::Relaxed) {
+            0 => (),
+            errno => return Err(std::io::Error::from_raw_os_error(errno)),
+        }
+
+        let fd = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let slot = FDS.iter().position(|x| {
+            x.compare_exchange(-1, fd, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        });
+
+        let slot = match slot {
+            Some(slot) => slot,
+            None => {
+                unsafe { libc::close(fd) };
+                return Err(std::io::Error::from_raw_os_error(libc::ENOLCK));
+            }
+        };
+
+        // Construct this now so that failures below clean up.
+        let lease = Self { fd, slot };
+
+        // Setting the signal explicitly causes the kernel to provide `si_fd`.
+        unsafe {
+            if libc::fcntl(fd, F_SETSIG, libc::SIGIO) != 0
+                || libc::fcntl(fd, libc::F_SETLEASE, libc::F_RDLCK) != 0
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+
+        Ok(lease)
+    }
+
+    /// Returns `true` if another process is waiting for the lease to be released
+    #[inline]
+    pub fn breaking(&self) -> bool {
+        unsafe { libc::fcntl(self.fd, libc::F_GETLEASE) != libc::F_RDLCK }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        unsafe {
+            libc::fcntl(self.fd, libc::F_SETLEASE, libc::F_UNLCK);
+            libc::close(self.fd);
+        }
+
+        FDS[self.slot].store(-1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page::PageSize;
+    use crate::{perms, Map, Private, Shared};
+
+    use std::io::Write;
+    use std::os::unix::fs::FileExt;
+    use std::time::{Duration, Instant};
+
+    fn path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("mmarinus-sigbus-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn truncated() {
+        let path = path("truncated");
+        let page = PageSize::base().bytes();
+
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(&vec![1u8; page * 2]).unwrap();
+
+        let mut map = Map::bytes(page * 2)
+            .anywhere()
+            .from(&mut file, 0)
+            .with_kind(Shared)
+            .with(perms::ReadWrite)
+            .unwrap();
+
+        let mut buf = vec![0u8; page * 2];
+        map.try_copy_to(0, &mut buf).unwrap();
+        assert_eq!(buf, vec![1u8; page * 2]);
+
+        file.set_len(page as u64).unwrap();
+
+        let err = map.try_copy_to(0, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(&buf[..page], &vec![1u8; page][..]);
+        assert_eq!(&buf[page..], &vec![0u8; page][..]);
+
+        // The first page is still valid.
+        map.try_copy_to(0, &mut buf[..page]).unwrap();
+        map.try_copy_from(0, &buf[..page]).unwrap();
+
+        file.set_len(0).unwrap();
+        let err = map.try_copy_from(0, &buf[..page]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+        // The mapping is still backed by the file.
+        file.set_len(page as u64 * 2).unwrap();
+        map.try_copy_from(page, &[2u8; 4]).unwrap();
+        file.write_all_at(&[3u8; 4], 0).unwrap();
+
+        let mut data = [0u8; 4];
+        file.read_exact_at(&mut data, page as u64).unwrap();
+        assert_eq!(data, [2u8; 4]);
+        map.try_copy_to(0, &mut data).unwrap();
+        assert_eq!(data, [3u8; 4]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn lease() {
+        let path = path("lease");
+        std::fs::write(&path, b"leased").unwrap();
+
+        let file = File::open(&path).unwrap();
+        let lease = match Lease::new(&file) {
+            Ok(lease) => lease,
+
+            // The filesystem might not support leases.
+            Err(_) => return std::fs::remove_file(&path).unwrap(),
+        };
+
+        let map = Map::load(&path, Private, perms::Read).unwrap();
+        assert!(!lease.breaking());
+
+        let writer = {
+            let path = path.clone();
+            std::thread::spawn(move || std::fs::OpenOptions::new().write(true).open(path))
+        };
+
+        let start = Instant::now();
+        while !lease.breaking() && start.elapsed() < Duration::from_secs(10) {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        assert!(lease.breaking());
+        assert_eq!(&*map, b"leased");
+        drop(lease);
+
+        writer.join().unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
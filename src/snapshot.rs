@@ -0,0 +1,499 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use super::backend::Backend;
+use super::map::{Readable, Writeable};
+use super::observer::{self, Event};
+use super::page::{PageSize, Tier};
+use super::{perms, Map, Private};
+
+#[cfg(feature = "tracking")]
+use super::tracking;
+
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::fs::FileExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::ptr::copy_nonoverlapping;
+
+/// The name of the memory files of snapshots
+const NAME: &str = "mmarinus-snapshot";
+
+/// The page is present (see `proc_pid_pagemap(5)`)
+const PM_PRESENT: u64 = 1 << 63;
+
+/// The page is swapped out
+const PM_SWAP: u64 = 1 << 62;
+
+/// The page is a page of a file or shared anonymous memory
+const PM_FILE: u64 = 1 << 61;
+
+/// The number of pages of `size` bytes, including the last partial page
+fn pages(size: usize, psize: usize) -> usize {
+    (size + psize - 1) / psize
+}
+
+/// The inodes of the memory files most recently created for snapshots
+///
+/// Nothing else can write to these files, since their descriptors are
+/// closed once they are mapped. Older files are forgotten, which is safe:
+/// their mappings are then copied as if they were anonymous.
+static FILES: [AtomicUsize; 64] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: AtomicUsize = AtomicUsize::new(0);
+    [EMPTY; 64]
+};
+
+/// The next slot of `FILES` to use
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+/// Finds the pages of the range which hold private data
+///
+/// These are the anonymous pages, either present or swapped out. All other
+/// pages of a private mapping are either untouched or pages of its file.
+fn private(addr: usize, size: usize, psize: usize) -> Result<Vec<bool>> {
+    let mut buf = vec![0u8; pages(size, psize) * 8];
+    let file = File::open("/proc/self/pagemap")?;
+    file.read_exact_at(&mut buf, (addr / psize * 8) as u64)?;
+
+    Ok(buf
+        .chunks(8)
+        .map(|e| u64::from_ne_bytes([e[0], e[1], e[2], e[3], e[4], e[5], e[6], e[7]]))
+        .map(|e| e & PM_SWAP != 0 || e & (PM_PRESENT | PM_FILE) == PM_PRESENT)
+        .collect())
+}
+
+/// What a range is mapped from
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Backing {
+    /// Anonymous memory only
+    Anonymous,
+
+    /// The memory files of snapshots only
+    Snapshot,
+
+    /// Anything else
+    Other,
+}
+
+impl Backing {
+    /// Finds out what the range is mapped from (see `proc_pid_maps(5)`)
+    fn of(addr: usize, size: usize) -> Self {
+        let maps = match std::fs::read_to_string("/proc/self/maps") {
+            Ok(maps) => maps,
+            Err(..) => return Self::Other,
+        };
+
+        let mut backing = None;
+        for line in maps.lines() {
+            let mut fields = line.split_whitespace();
+            let range = fields.next().unwrap_or_default();
+            let mut bounds = range.split('-').map(|n| usize::from_str_radix(n, 16));
+            let (start, end) = match (bounds.next(), bounds.next()) {
+                (Some(Ok(start)), Some(Ok(end))) => (start, end),
+                _ => return Self::Other,
+            };
+
+            if end <= addr || start >= addr + size {
+                continue;
+            }
+
+            let inode = fields.nth(3).and_then(|i| i.parse::<usize>().ok());
+            let this = match (fields.next(), inode) {
+                (None, _) => Self::Anonymous,
+                (Some(path), Some(inode))
+                    if path == format!("/memfd:{}", NAME)
+                        && FILES.iter().any(|f| f.load(Ordering::Relaxed) == inode) =>
+                {
+                    Self::Snapshot
+                }
+                _ => Self::Other,
+            };
+
+            match backing {
+                Some(backing) if backing != this => return Self::Other,
+                _ => backing = Some(this),
+            }
+        }
+
+        backing.unwrap_or(Self::Other)
+    }
+}
+
+impl<T: Readable + Writeable, B: Backend> Map<T, Private, B> {
+    /// Takes a point-in-time snapshot of the mapping
+    ///
+    /// The first snapshot of a mapping copies the pages which hold data into
+    /// an anonymous memory file (see `memfd_create(2)`). Then both this
+    /// mapping and the returned snapshot are mapped privately from that
+    /// file. Until either of them is written to, they share the same
+    /// physical pages; afterwards, pages are copied lazily, one page at a
+    /// time, as they are modified. Writes to this mapping are never visible
+    /// in the snapshot.
+    ///
+    /// Later snapshots only copy the pages which were modified since the
+    /// previous one: the pages of this mapping are moved to the snapshot
+    /// with `mremap()` and `MREMAP_DONTUNMAP`, and the modified pages are
+    /// copied back. This needs Linux 5.13; on older kernels, every snapshot
+    /// copies the whole mapping.
+    ///
+    /// If this mapping cannot be replaced by the mapping of the memory file,
+    /// the error is returned and the mapping keeps its address and contents.
+    ///
+    /// # Example
+    /// ```
+    /// use mmarinus::{Map, perms};
+    ///
+    /// let mut map = Map::bytes(4096)
+    ///     .anywhere()
+    ///     .anonymously()
+    ///     .with(perms::ReadWrite)
+    ///     .unwrap();
+    ///
+    /// map[0] = 1;
+    /// let snapshot = map.snapshot().unwrap();
+    /// map[0] = 2;
+    /// assert_eq!(snapshot[0], 1);
+    ///
+    /// map.restore(&snapshot).unwrap();
+    /// assert_eq!(map[0], 1);
+    /// ```
+    pub fn snapshot(&mut self) -> Result<Map<perms::Read, Private, B>> {
+        if self.size == 0 {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
+        let psize = PageSize::base().bytes();
+        let private = private(self.addr, self.size, psize).ok();
+
+        match (Backing::of(self.addr, self.size), private) {
+            (Backing::Snapshot, Some(private)) => match self.detach(&private, psize)? {
+                Some(snapshot) => Ok(snapshot),
+                None => self.copy(None, psize),
+            },
+
+            (Backing::Anonymous, Some(private)) => self.copy(Some(&private), psize),
+            _ => self.copy(None, psize),
+        }
+    }
+
+    /// Moves the pages to a snapshot, keeping the private pages
+    ///
+    /// Returns `None` if the kernel cannot move the pages of the mapping.
+    fn detach(
+        &mut self,
+        private: &[bool],
+        psize: usize,
+    ) -> Result<Option<Map<perms::Read, Private, B>>> {
+        let reserved = Map::bytes(self.size)
+            .anywhere()
+            .anonymously()
+            .with_backend::<B>()
+            .with(perms::None)?;
+
+        let flags = libc::MREMAP_MAYMOVE | libc::MREMAP_FIXED | libc::MREMAP_DONTUNMAP;
+        let addr = reserved.addr();
+        let ret = unsafe { B::mremap(self.addr, self.size, self.size, flags, addr) };
+        if let Some(observer) = observer::get::<B>() {
+            let event = Event {
+                addr,
+                ..self.event(None, ret.as_ref().err())
+            };
+
+            observer.on_remap(&event);
+        }
+
+        match ret {
+            Err(err) if err.raw_os_error() == Some(libc::EINVAL) => return Ok(None),
+            Err(err) => return Err(err.into()),
+            Ok(..) => reserved.into_raw(),
+        };
+
+        let snapshot: Self = Map {
+            addr,
+            size: self.size,
+            page: self.page,
+            tier: self.tier,
+            data: PhantomData,
+        };
+
+        // This mapping now reads the file of the previous snapshot again.
+        for (i, _) in private.iter().enumerate().filter(|(_, p)| **p) {
+            let offset = i * psize;
+            let len = psize.min(self.size - offset);
+            unsafe {
+                let src = (snapshot.addr + offset) as *const u8;
+                copy_nonoverlapping(src, (self.addr + offset) as *mut u8, len);
+            }
+        }
+
+        Ok(Some(snapshot.reprotect(perms::Read)?))
+    }
+
+    /// Copies the mapping into a new memory file and maps it from there
+    ///
+    /// Without `private`, all pages are copied. Otherwise, the other pages
+    /// are assumed to be untouched, so they stay holes in the file.
+    fn copy(
+        &mut self,
+        private: Option<&[bool]>,
+        psize: usize,
+    ) -> Result<Map<perms::Read, Private, B>> {
+        let name = format!("{}\0", NAME);
+        let fd = unsafe { libc::memfd_create(name.as_ptr() as _, libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        let size = u64::try_from(self.size).or(Err(ErrorKind::InvalidInput))?;
+        file.set_len(size)?;
+
+        let pages = pages(self.size, psize);
+        let mut page = 0;
+        while page < pages {
+            let copied = |p: usize| private.map_or(true, |private| private[p]);
+            if !copied(page) {
+                page += 1;
+                continue;
+            }
+
+            let start = page;
+            while page < pages && copied(page) {
+                page += 1;
+            }
+
+            let range = start * psize..self.size.min(page * psize);
+            file.write_all_at(&self[range], (start * psize) as u64)?;
+        }
+
+        let snapshot = Map::bytes(self.size)
+            .anywhere()
+            .from(&mut file, 0)
+            .with_backend::<B>()
+            .with(perms::Read)?;
+
+        let (addr, size) = Map::bytes(self.size)
+            .anywhere()
+            .from(&mut file, 0)
+            .with_backend::<B>()
+            .with(perms::Unknown(T::VALUE))?
+            .into_raw();
+
+        let copy = unsafe { Self::from_raw(addr, size) };
+
+        let slot = NEXT.fetch_add(1, Ordering::Relaxed) % FILES.len();
+        FILES[slot].store(file.metadata()?.ino() as usize, Ordering::Relaxed);
+
+        // Share the pages of the memory file until they are modified.
+        let flags = libc::MREMAP_MAYMOVE | libc::MREMAP_FIXED;
+        let ret = unsafe { B::mremap(copy.addr, size, size, flags, self.addr) };
+        if let Some(observer) = observer::get::<B>() {
+            let event = Event {
+                addr: self.addr,
+                ..copy.event(None, ret.as_ref().err())
+            };
+
+            observer.on_remap(&event);
+        }
+
+        if let Err(err) = ret {
+            // The kernel unmaps the target before moving the pages, so this
+            // mapping may be gone. The memory file holds its contents, so
+            // it is mapped from there again, at the same address.
+            if unsafe { libc::msync(self.addr as _, self.size, libc::MS_ASYNC) } != 0 {
+                let flags = libc::MAP_PRIVATE | libc::MAP_FIXED;
+                let prot = T::VALUE;
+                let fd = file.as_raw_fd();
+                if unsafe { B::mmap(self.addr, self.size, prot, flags, fd, 0) }.is_err() {
+                    // Nothing can be mapped in place of the mapping, which
+                    // would then be unmapped by its owner when dropped.
+                    std::process::abort();
+                }
+
+                self.page = PageSize::base();
+                self.tier = Tier::Base;
+            }
+
+            return Err(err.into());
+        }
+
+        #[cfg(feature = "tracking")]
+        if B::HOOKS {
+            tracking::moved(copy.addr, self.addr, size);
+        }
+
+        copy.into_raw();
+        self.page = PageSize::base();
+        self.tier = Tier::Base;
+        Ok(snapshot)
+    }
+
+    /// Rolls the mapping back to the contents of `snapshot`
+    ///
+    /// Only the pages which differ from the snapshot are copied, so pages
+    /// which have not been modified since the snapshot was taken remain
+    /// shared with it.
+    ///
+    /// Fails with `ErrorKind::InvalidInput` if the snapshot is not the same
+    /// size as this mapping.
+    pub fn restore(&mut self, snapshot: &Map<perms::Read, Private, B>) -> Result<()> {
+        if snapshot.size != self.size {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
+        let psize = PageSize::base().bytes();
+        for (dst, src) in self.chunks_mut(psize).zip(snapshot.chunks(psize)) {
+            if dst != src {
+                dst.copy_from_slice(src);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{Mock, Syscall};
+
+    #[test]
+    fn snapshot() {
+        let psize = PageSize::base().bytes();
+
+        let mut map = Map::bytes(psize * 4)
+            .anywhere()
+            .anonymously()
+            .with(perms::ReadWrite)
+            .unwrap();
+
+        for (i, page) in map.chunks_mut(psize).enumerate() {
+            page.iter_mut().for_each(|b| *b = i as u8);
+        }
+
+        let first = map.snapshot().unwrap();
+        assert_eq!(&*first, &*map);
+        assert_eq!(Backing::of(map.addr(), map.size()), Backing::Snapshot);
+
+        map[psize] = 0xff;
+        let second = map.snapshot().unwrap();
+        map[psize * 3] = 0xff;
+
+        assert_eq!(first[psize], 1);
+        assert_eq!(second[psize], 0xff);
+        assert_eq!(second[psize * 3], 3);
+
+        map.restore(&second).unwrap();
+        assert_eq!(&*map, &*second);
+
+        map.restore(&first).unwrap();
+        assert_eq!(&*map, &*first);
+    }
+
+    #[test]
+    fn private() {
+        let psize = PageSize::base().bytes();
+
+        let mut map = Map::bytes(psize * 3)
+            .anywhere()
+            .anonymously()
+            .with(perms::ReadWrite)
+            .unwrap();
+
+        map[psize] = 1;
+        let snapshot = map.snapshot().unwrap();
+        assert_eq!(snapshot[psize], 1);
+
+        // Only modified pages are private to the mapping.
+        map[psize * 2] = 2;
+        let private = super::private(map.addr(), map.size(), psize).unwrap();
+        assert_eq!(private, vec![false, false, true]);
+    }
+
+    #[test]
+    fn partial() {
+        let psize = PageSize::base().bytes();
+
+        let mut map = Map::bytes(psize + 100)
+            .anywhere()
+            .anonymously()
+            .with(perms::ReadWrite)
+            .unwrap();
+
+        map[psize + 50] = 9;
+        let first = map.snapshot().unwrap();
+        assert_eq!(first[psize + 50], 9);
+        assert_eq!(map[psize + 50], 9);
+
+        map[psize + 50] = 7;
+        let second = map.snapshot().unwrap();
+        assert_eq!(first[psize + 50], 9);
+        assert_eq!(second[psize + 50], 7);
+        assert_eq!(map[psize + 50], 7);
+    }
+
+    #[test]
+    fn foreign() {
+        let psize = PageSize::base().bytes();
+        let name = format!("{}\0", NAME);
+        let fd = unsafe { libc::memfd_create(name.as_ptr() as _, libc::MFD_CLOEXEC) };
+        assert!(fd >= 0);
+
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        file.set_len(psize as u64).unwrap();
+        let map = Map::bytes(psize)
+            .anywhere()
+            .from(&mut file, 0)
+            .with(perms::ReadWrite)
+            .unwrap();
+
+        // A memory file with the same name is not one of the snapshots.
+        assert_eq!(Backing::of(map.addr(), map.size()), Backing::Other);
+    }
+
+    #[test]
+    fn failure() {
+        Mock::reset();
+
+        let psize = PageSize::base().bytes();
+        let mut map = Map::bytes(psize * 2)
+            .anywhere()
+            .anonymously()
+            .with_backend::<Mock>()
+            .with(perms::ReadWrite)
+            .unwrap();
+
+        map[psize] = 1;
+        let addr = map.addr();
+        Mock::fail(Syscall::Mremap, libc::ENOMEM);
+        let err = map.snapshot().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOMEM));
+
+        // The mapping keeps its address and contents and can still be
+        // written to.
+        assert_eq!(map.addr(), addr);
+        assert_eq!(map[psize], 1);
+        map[psize] = 2;
+        assert_eq!(map.snapshot().unwrap()[psize], 2);
+        Mock::reset();
+    }
+
+    #[test]
+    fn mismatch() {
+        let psize = PageSize::base().bytes();
+        let mut map = Map::bytes(psize * 2)
+            .anywhere()
+            .anonymously()
+            .with(perms::ReadWrite)
+            .unwrap();
+
+        let snapshot = map.snapshot().unwrap();
+        let (mut l, _) = map.split(psize).unwrap();
+        let err = l.restore(&snapshot).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,312 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Stacks for threads, coroutines and signal handlers
+
+use super::errno;
+use super::page::PageSize;
+use super::{perms, Error, Flags, Map};
+
+use std::any::Any;
+use std::io::ErrorKind;
+use std::marker::PhantomData;
+use std::mem::{ManuallyDrop, MaybeUninit};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+// Not exported by older versions of libc.
+extern "C" {
+    fn pthread_attr_setstack(
+        attr: *mut libc::pthread_attr_t,
+        addr: *mut libc::c_void,
+        size: libc::size_t,
+    ) -> libc::c_int;
+}
+
+/// The alignment of the stack pointer required by the architecture ABI
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64",
+    target_arch = "powerpc64",
+    target_arch = "s390x"
+))]
+pub const ALIGN: usize = 16;
+
+/// The alignment of the stack pointer required by the architecture ABI
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64",
+    target_arch = "powerpc64",
+    target_arch = "s390x"
+)))]
+pub const ALIGN: usize = 8;
+
+/// A stack allocated from a mapping
+///
+/// The stack is created with `MAP_STACK` and a `PROT_NONE` guard page is
+/// placed directly below its lowest address. Since stacks grow downward, an
+/// overflow faults on the guard page instead of silently corrupting
+/// whatever memory happens to be below the stack.
+///
+/// ```rust
+/// use mmarinus::stack::Stack;
+///
+/// let stack = Stack::new(256 * 1024).unwrap();
+/// assert!(stack.size() >= 256 * 1024);
+///
+/// let thread = stack.spawn(|| 2 + 2).unwrap();
+/// assert_eq!(thread.join().unwrap(), 4);
+/// ```
+#[derive(Debug)]
+pub struct Stack {
+    guard: Map<perms::None>,
+    map: Map<perms::ReadWrite>,
+}
+
+impl Stack {
+    /// Allocates a new stack of at least `size` bytes
+    ///
+    /// The size is rounded up to a multiple of the page size. The guard page
+    /// is allocated in addition to the requested size.
+    pub fn new(size: usize) -> Result<Self, Error<()>> {
+        let psize = PageSize::base().bytes();
+        let size = match PageSize::base().round_up(size) {
+            Some(size) if size > 0 => size,
+            _ => return Err(ErrorKind::InvalidInput.into()),
+        };
+
+        let total = size.checked_add(psize).ok_or(ErrorKind::InvalidInput)?;
+        let map = Map::bytes(total)
+            .anywhere()
+            .anonymously()
+            .with_flags(Flags::STACK | Flags::NORESERVE)
+            .with(perms::ReadWrite)?;
+
+        let (guard, map) = map.split(psize).map_err(|e| e.err)?;
+        let guard = guard.reprotect(perms::None).map_err(|e| e.err)?;
+        Ok(Self { guard, map })
+    }
+
+    /// Gets the lowest usable address of the stack
+    #[inline]
+    pub fn bottom(&self) -> usize {
+        self.map.addr()
+    }
+
+    /// Gets the initial stack pointer
+    ///
+    /// This is the highest address of the stack, aligned down to the
+    /// alignment required by the architecture ABI (see `ALIGN`).
+    #[inline]
+    pub fn top(&self) -> usize {
+        (self.map.addr() + self.map.size()) & !(ALIGN - 1)
+    }
+
+    /// Gets the usable size of the stack (excluding the guard page)
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.map.size()
+    }
+
+    /// Gets the address of the guard page
+    #[inline]
+    pub fn guard(&self) -> usize {
+        self.guard.addr()
+    }
+
+    /// Runs a closure on a new thread which uses this stack
+    ///
+    /// This is similar to `std::thread::spawn()` except that the thread runs
+    /// on this stack rather than on a stack allocated by the system. Note
+    /// that the C library reserves space for the thread descriptor and the
+    /// thread-local storage at the top of the stack.
+    ///
+    /// If the thread cannot be created, the stack is returned in the error.
+    pub fn spawn<F, R>(self, f: F) -> Result<JoinHandle<R>, Error<Self>>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        extern "C" fn start<F: FnOnce() -> R, R>(arg: *mut libc::c_void) -> *mut libc::c_void {
+            let f = unsafe { Box::from_raw(arg as *mut F) };
+            let ret = catch_unwind(AssertUnwindSafe(move || (*f)()));
+            Box::into_raw(Box::new(ret)) as *mut _
+        }
+
+        let arg = Box::into_raw(Box::new(f));
+
+        let mut thread = MaybeUninit::uninit();
+        let mut attr = MaybeUninit::uninit();
+        let ret = unsafe {
+            match libc::pthread_attr_init(attr.as_mut_ptr()) {
+                0 => {
+                    let attr = attr.as_mut_ptr();
+                    let ret = match pthread_attr_setstack(attr, self.bottom() as _, self.size()) {
+                        0 => {
+                            libc::pthread_create(thread.as_mut_ptr(), attr, start::<F, R>, arg as _)
+                        }
+                        e => e,
+                    };
+
+                    libc::pthread_attr_destroy(attr);
+                    ret
+                }
+                e => e,
+            }
+        };
+
+        if ret != 0 {
+            drop(unsafe { Box::from_raw(arg) });
+            return Err(Error::new(self, errno::from_raw(ret)));
+        }
+
+        Ok(JoinHandle {
+            thread: unsafe { thread.assume_init() },
+            stack: self,
+            data: PhantomData,
+        })
+    }
+
+    /// Installs this stack as the alternate signal stack of this thread
+    ///
+    /// Signal handlers installed with `SA_ONSTACK` will run on this stack.
+    /// The previous alternate signal stack is restored when the returned
+    /// value is dropped.
+    pub fn sigaltstack(&self) -> std::io::Result<AltStack<'_>> {
+        let new = libc::stack_t {
+            ss_sp: self.bottom() as _,
+            ss_flags: 0,
+            ss_size: self.size(),
+        };
+
+        let mut old = MaybeUninit::uninit();
+        if unsafe { libc::sigaltstack(&new, old.as_mut_ptr()) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(AltStack {
+            old: unsafe { old.assume_init() },
+            data: PhantomData,
+        })
+    }
+}
+
+/// A handle to a thread running on a `Stack`
+///
+/// If the handle is dropped without calling `join()`, the drop blocks until
+/// the thread exits since the thread is still using the stack.
+#[derive(Debug)]
+pub struct JoinHandle<R> {
+    thread: libc::pthread_t,
+    stack: Stack,
+    data: PhantomData<R>,
+}
+
+impl<R> JoinHandle<R> {
+    /// Waits for the thread to finish and returns its result
+    ///
+    /// If the closure panicked, the panic payload is returned as an error.
+    #[inline]
+    pub fn join(self) -> std::thread::Result<R> {
+        self.join_with_stack().0
+    }
+
+    /// Waits for the thread to finish and returns its result and its stack
+    ///
+    /// This allows stacks to be reused for other threads.
+    pub fn join_with_stack(self) -> (std::thread::Result<R>, Stack) {
+        let mut this = ManuallyDrop::new(self);
+        let ret = this.wait();
+        (ret, unsafe { std::ptr::read(&this.stack) })
+    }
+
+    fn wait(&mut self) -> std::thread::Result<R> {
+        let mut ret = std::ptr::null_mut();
+        match unsafe { libc::pthread_join(self.thread, &mut ret) } {
+            0 if !ret.is_null() => *unsafe { Box::from_raw(ret as *mut std::thread::Result<R>) },
+            _ => Err(Box::new("unable to join thread") as Box<dyn Any + Send>),
+        }
+    }
+}
+
+impl<R> Drop for JoinHandle<R> {
+    fn drop(&mut self) {
+        let _ = self.wait();
+    }
+}
+
+/// An installed alternate signal stack
+///
+/// Restores the previous alternate signal stack when dropped. Since the
+/// alternate signal stack is a per-thread setting, this value cannot be
+/// sent to other threads.
+pub struct AltStack<'a> {
+    old: libc::stack_t,
+    data: PhantomData<(&'a Stack, *const ())>,
+}
+
+impl Drop for AltStack<'_> {
+    fn drop(&mut self) {
+        unsafe { libc::sigaltstack(&self.old, std::ptr::null_mut()) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout() {
+        let stack = Stack::new(1).unwrap();
+        let psize = PageSize::base().bytes();
+
+        assert_eq!(stack.size(), psize);
+        assert_eq!(stack.guard() + psize, stack.bottom());
+        assert_eq!(stack.top() % ALIGN, 0);
+        assert!(stack.top() <= stack.bottom() + stack.size());
+    }
+
+    #[test]
+    fn spawn() {
+        let stack = Stack::new(1024 * 1024).unwrap();
+        let (bottom, top) = (stack.bottom(), stack.top());
+
+        let thread = stack
+            .spawn(move || {
+                let local = 0u8;
+                let addr = &local as *const u8 as usize;
+                assert!(addr >= bottom && addr < top);
+                17
+            })
+            .unwrap();
+
+        let (ret, stack) = thread.join_with_stack();
+        assert_eq!(ret.unwrap(), 17);
+
+        let thread = stack
+            .spawn(|| std::panic::resume_unwind(Box::new(())))
+            .unwrap();
+        assert!(thread.join().is_err());
+    }
+
+    #[test]
+    fn sigaltstack() {
+        let stack = Stack::new(libc::SIGSTKSZ * 4).unwrap();
+
+        let alt = stack.sigaltstack().unwrap();
+
+        let mut cur = MaybeUninit::uninit();
+        assert_eq!(
+            unsafe { libc::sigaltstack(std::ptr::null(), cur.as_mut_ptr()) },
+            0
+        );
+        assert_eq!(unsafe { cur.assume_init() }.ss_sp as usize, stack.bottom());
+
+        drop(alt);
+
+        assert_eq!(
+            unsafe { libc::sigaltstack(std::ptr::null(), cur.as_mut_ptr()) },
+            0
+        );
+        assert_ne!(unsafe { cur.assume_init() }.ss_sp as usize, stack.bottom());
+    }
+}
@@ -0,0 +1,503 @@
+// SPDX-License-Identifier: Apache-2.0
+//! Process-wide accounting of live mappings
+//!
+//! With the `tracking` feature, every mapping created by `Builder::with()`
+//! is recorded along with its size, permissions, kind and an optional label
+//! (see `with_label()` on the builder). The records follow the mappings as
+//! they are split and reprotected and are removed when the mappings are
+//! dropped. This is useful for finding leaks:
+//!
+//! ```rust
+//! use mmarinus::{tracking, Map, perms};
+//!
+//! let map = Map::bytes(4096)
+//!     .anywhere()
+//!     .anonymously()
+//!     .with_label("doc")
+//!     .with(perms::Read)
+//!     .unwrap();
+//!
+//! let records = tracking::snapshot();
+//! let record = records.iter().find(|r| r.addr == map.addr()).unwrap();
+//! assert_eq!(record.label, Some("doc"));
+//! assert_eq!(record.prot, libc::PROT_READ);
+//! ```
+//!
+//! A budget limits the total size of the live mappings. Once the budget
+//! would be exceeded, `Builder::with()` fails before calling `mmap()` with
+//! `Reason::BudgetExceeded` (and `EDQUOT`). Mappings which replace others
+//! (see `Map::remap()`) only count the bytes which were not mapped before.
+//!
+//! The mappings of the allocators in the `alloc` module are not recorded
+//! (see `Backend::HOOKS`). The registry itself does not use the heap, so
+//! it is safe to use while any global allocator is active.
+
+use std::cell::UnsafeCell;
+use std::mem::size_of;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// A live mapping
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Record {
+    /// The address
+    pub addr: usize,
+
+    /// The size
+    pub size: usize,
+
+    /// The permissions (`PROT_*`)
+    pub prot: libc::c_int,
+
+    /// The kind (`MAP_PRIVATE`, `MAP_SHARED`, ...)
+    pub kind: libc::c_int,
+
+    /// The label given to the builder, if any
+    pub label: Option<&'static str>,
+}
+
+/// The records, sorted by address, in memory which is mapped directly
+struct Table {
+    ptr: *mut Record,
+    len: usize,
+    cap: usize,
+}
+
+struct Registry {
+    table: Table,
+    mapped: usize,
+    pending: usize,
+    budget: Option<usize>,
+}
+
+struct Global {
+    lock: AtomicBool,
+    registry: UnsafeCell<Registry>,
+}
+
+// The registry is only accessed with the lock held.
+unsafe impl Sync for Global {}
+
+static GLOBAL: Global = Global {
+    lock: AtomicBool::new(false),
+    registry: UnsafeCell::new(Registry {
+        table: Table {
+            ptr: null_mut(),
+            len: 0,
+            cap: 0,
+        },
+        mapped: 0,
+        pending: 0,
+        budget: None,
+    }),
+};
+
+fn locked<R>(f: impl FnOnce(&mut Registry) -> R) -> R {
+    while (GLOBAL.lock)
+        .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        std::hint::spin_loop();
+    }
+
+    let ret = f(unsafe { &mut *GLOBAL.registry.get() });
+    GLOBAL.lock.store(false, Ordering::Release);
+    ret
+}
+
+impl Table {
+    fn records(&self) -> &[Record] {
+        match self.len {
+            0 => &[],
+            n => unsafe { std::slice::from_raw_parts(self.ptr, n) },
+        }
+    }
+
+    fn find(&self, addr: usize) -> Result<usize, usize> {
+        self.records().binary_search_by_key(&addr, |r| r.addr)
+    }
+
+    /// Removes the records starting in `start..end`
+    ///
+    /// Returns the record starting at `start`, if any, and the total size
+    /// of the removed records.
+    fn drain(&mut self, start: usize, end: usize) -> (Option<Record>, usize) {
+        let (first, index) = match self.find(start) {
+            Ok(index) => (Some(self.records()[index]), index),
+            Err(index) => (None, index),
+        };
+
+        let removed = &self.records()[index..];
+        let count = removed.iter().take_while(|r| r.addr < end).count();
+        let bytes = removed[..count].iter().map(|r| r.size).sum();
+
+        unsafe {
+            let tail = self.len - index - count;
+            self.ptr
+                .add(index)
+                .copy_from(self.ptr.add(index + count), tail);
+        }
+
+        self.len -= count;
+        (first.filter(|_| count > 0), bytes)
+    }
+
+    /// Gets the number of bytes of the records in `addr..addr + size`
+    fn overlap(&self, addr: usize, size: usize) -> usize {
+        let end = addr.saturating_add(size);
+        let index = match self.find(addr) {
+            Ok(index) => index,
+            Err(index) => index.saturating_sub(1),
+        };
+
+        self.records()[index..]
+            .iter()
+            .take_while(|r| r.addr < end)
+            .map(|r| {
+                r.addr
+                    .saturating_add(r.size)
+                    .min(end)
+                    .saturating_sub(r.addr.max(addr))
+            })
+            .sum()
+    }
+
+    /// Inserts a record, unless there is no memory for it
+    fn insert(&mut self, record: Record) -> bool {
+        let index = match self.find(record.addr) {
+            Ok(index) => {
+                unsafe { self.ptr.add(index).write(record) };
+                return true;
+            }
+
+            Err(index) => index,
+        };
+
+        if self.len == self.cap && !self.grow() {
+            return false;
+        }
+
+        unsafe {
+            let tail = self.len - index;
+            self.ptr.add(index + 1).copy_from(self.ptr.add(index), tail);
+            self.ptr.add(index).write(record);
+        }
+
+        self.len += 1;
+        true
+    }
+
+    /// Doubles the capacity of the table
+    fn grow(&mut self) -> bool {
+        let page = crate::page::PageSize::base().bytes();
+        let size = size_of::<Record>();
+        let bytes = (self.cap * size * 2).max(page);
+
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+        let ptr = unsafe { libc::mmap(null_mut(), bytes, prot, flags, -1, 0) };
+        if ptr == libc::MAP_FAILED {
+            return false;
+        }
+
+        unsafe {
+            let ptr = ptr as *mut Record;
+            if self.cap > 0 {
+                ptr.copy_from_nonoverlapping(self.ptr, self.len);
+                libc::munmap(self.ptr as _, self.cap * size);
+            }
+
+            self.ptr = ptr;
+            self.cap = bytes / size;
+        }
+
+        true
+    }
+}
+
+impl Registry {
+    /// Records a mapping, replacing the records of any mappings it overlays
+    fn insert(&mut self, record: Record) {
+        self.remove(record.addr, record.size);
+        if record.size > 0 && self.table.insert(record) {
+            self.mapped += record.size;
+        }
+    }
+
+    /// Removes the records of the mappings in the range
+    ///
+    /// Returns the record of the mapping starting at `addr`, if any.
+    fn remove(&mut self, addr: usize, size: usize) -> Option<Record> {
+        let (first, bytes) = self.table.drain(addr, addr.saturating_add(size));
+        self.mapped = self.mapped.saturating_sub(bytes);
+        first
+    }
+}
+
+/// Takes a snapshot of the live mappings, sorted by address
+pub fn snapshot() -> Vec<Record> {
+    // Allocate outside of the lock, in case the global allocator maps.
+    let mut records = Vec::new();
+    loop {
+        let len = locked(|r| r.table.len);
+        records.reserve(len);
+
+        let done = locked(|r| match r.table.len <= records.capacity() {
+            false => false,
+            true => {
+                records.extend_from_slice(r.table.records());
+                true
+            }
+        });
+
+        if done {
+            return records;
+        }
+    }
+}
+
+/// Gets the total size of the live mappings
+pub fn mapped() -> usize {
+    locked(|r| r.mapped)
+}
+
+/// Gets the budget for the total size of the live mappings, if any
+pub fn budget() -> Option<usize> {
+    locked(|r| r.budget)
+}
+
+/// Sets the budget for the total size of the live mappings
+///
+/// Lowering the budget below the size of the live mappings does not affect
+/// them, but all new mappings fail until enough of them are dropped.
+pub fn set_budget(budget: Option<usize>) {
+    locked(|r| r.budget = budget)
+}
+
+/// Reserves `size` bytes of the budget for a new mapping
+///
+/// If the mapping replaces the pages at `onto` (see `Builder::onto()`),
+/// only the bytes which are not mapped yet count against the budget.
+///
+/// Returns `false` if the budget would be exceeded. Otherwise, the caller
+/// must call either `commit()` or `release()` with the same size.
+pub(crate) fn reserve(size: usize, onto: Option<usize>) -> bool {
+    locked(|r| {
+        let replaced = onto.map_or(0, |addr| r.table.overlap(addr, size));
+        let total = r.mapped.saturating_add(r.pending).saturating_add(size);
+        let total = total.saturating_sub(replaced);
+        match r.budget {
+            Some(budget) if total > budget => false,
+            _ => {
+                r.pending += size;
+                true
+            }
+        }
+    })
+}
+
+/// Records a new mapping for which `reserved` bytes were reserved
+pub(crate) fn commit(reserved: usize, record: Record) {
+    locked(|r| {
+        r.pending -= reserved;
+        r.insert(record);
+    })
+}
+
+/// Releases a reservation whose mapping could not be created
+pub(crate) fn release(reserved: usize) {
+    locked(|r| r.pending -= reserved)
+}
+
+/// Removes the records of the mappings in the unmapped range
+pub(crate) fn unmap(addr: usize, size: usize) {
+    locked(|r| {
+        r.remove(addr, size);
+    })
+}
+
+/// Splits the record of the mapping at `addr` at `offset`
+pub(crate) fn split(addr: usize, offset: usize) {
+    locked(|r| {
+        if let Some(old) = r.remove(addr, 1) {
+            r.insert(Record {
+                size: offset.min(old.size),
+                ..old
+            });
+
+            r.insert(Record {
+                addr: addr + offset,
+                size: old.size.saturating_sub(offset),
+                ..old
+            });
+        }
+    })
+}
+
+/// Updates the record of the mapping at `addr`
+pub(crate) fn update(addr: usize, f: impl FnOnce(&mut Record)) {
+    locked(|r| {
+        if let Some(mut record) = r.remove(addr, 1) {
+            f(&mut record);
+            r.insert(record);
+        }
+    })
+}
+
+/// Moves the record of the mapping at `addr` to `new` (see `mremap()`)
+pub(crate) fn moved(addr: usize, new: usize, size: usize) {
+    update(addr, |r| {
+        r.addr = new;
+        r.size = size;
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page::PageSize;
+    use crate::{perms, Map, Reason, Shared};
+
+    fn find(addr: usize) -> Option<Record> {
+        snapshot().into_iter().find(|r| r.addr == addr)
+    }
+
+    static BUDGETED: AtomicBool = AtomicBool::new(false);
+
+    /// A budget which is set for the lifetime of a test
+    ///
+    /// Only one test at a time may set the budget. The budget is removed
+    /// again when the guard is dropped, even if the test panics.
+    struct Budgeted;
+
+    impl Budgeted {
+        fn set(budget: usize) -> Self {
+            while BUDGETED
+                .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                std::thread::yield_now();
+            }
+
+            set_budget(Some(budget));
+            Self
+        }
+    }
+
+    impl Drop for Budgeted {
+        fn drop(&mut self) {
+            set_budget(None);
+            BUDGETED.store(false, Ordering::Release);
+        }
+    }
+
+    #[test]
+    fn lifecycle() {
+        let psize = PageSize::base().bytes();
+        let map = Map::bytes(psize * 4)
+            .anywhere()
+            .anonymously()
+            .with_kind(Shared)
+            .with_label("lifecycle")
+            .with(perms::ReadWrite)
+            .unwrap();
+
+        let addr = map.addr();
+        let record = find(addr).unwrap();
+        assert_eq!(record.size, psize * 4);
+        assert_eq!(record.prot, libc::PROT_READ | libc::PROT_WRITE);
+        assert_eq!(record.kind, libc::MAP_SHARED);
+        assert_eq!(record.label, Some("lifecycle"));
+
+        let (l, r) = map.split(psize).unwrap();
+        assert_eq!(find(addr).unwrap().size, psize);
+        assert_eq!(find(addr + psize).unwrap().size, psize * 3);
+        assert_eq!(find(addr + psize).unwrap().label, Some("lifecycle"));
+
+        let r = r.reprotect(perms::Read).unwrap();
+        assert_eq!(find(r.addr()).unwrap().prot, libc::PROT_READ);
+
+        drop(l);
+        assert_eq!(find(addr), None);
+        drop(r);
+        assert_eq!(find(addr + psize), None);
+    }
+
+    #[test]
+    fn budget() {
+        // Other tests may map memory concurrently, but they map far less than
+        // the slack left by these budgets.
+        const HUGE: usize = 1 << 40;
+
+        let budgeted = Budgeted::set(mapped() + HUGE / 2);
+        let err = Map::bytes(HUGE)
+            .anywhere()
+            .anonymously()
+            .with_flags(crate::Flags::NORESERVE)
+            .with(perms::None)
+            .unwrap_err();
+        drop(budgeted);
+
+        assert_eq!(err.reason(), Reason::BudgetExceeded);
+        assert_eq!(err.err.raw_os_error(), Some(libc::EDQUOT));
+        assert_eq!(super::budget(), None);
+
+        // Remapping only needs the budget for the bytes it adds.
+        let map = Map::bytes(HUGE / 4)
+            .anywhere()
+            .anonymously()
+            .with_flags(crate::Flags::NORESERVE)
+            .with(perms::None)
+            .unwrap();
+
+        let budgeted = Budgeted::set(mapped() + HUGE / 8);
+        let map = map
+            .remap()
+            .anonymously()
+            .with_flags(crate::Flags::NORESERVE)
+            .with(perms::None);
+
+        let err = Map::bytes(HUGE / 4)
+            .anywhere()
+            .anonymously()
+            .with_flags(crate::Flags::NORESERVE)
+            .with(perms::None)
+            .unwrap_err();
+        drop(budgeted);
+
+        assert!(map.is_ok());
+        assert_eq!(err.reason(), Reason::BudgetExceeded);
+    }
+
+    #[test]
+    fn table() {
+        let mut table = Table {
+            ptr: null_mut(),
+            len: 0,
+            cap: 0,
+        };
+
+        let record = |addr| Record {
+            addr,
+            size: 1,
+            prot: 0,
+            kind: 0,
+            label: None,
+        };
+
+        // Enough records to grow the table a few times.
+        for addr in (0..10_000).rev() {
+            assert!(table.insert(record(addr * 2)));
+        }
+
+        assert!(table.insert(record(10)));
+        assert_eq!(table.len, 10_000);
+        assert!(table.records().windows(2).all(|w| w[0].addr < w[1].addr));
+
+        assert_eq!(table.overlap(9, 4), 2);
+        assert_eq!(table.overlap(11, 1), 0);
+        assert_eq!(table.drain(11, 12), (None, 0));
+        assert_eq!(table.drain(10, 15), (Some(record(10)), 3));
+        assert_eq!(table.find(16), Ok(5));
+    }
+}